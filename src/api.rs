@@ -1,17 +1,29 @@
 use std::{
     io::{self},
+    ops::Deref,
+    sync::Arc,
     time::Duration,
 };
 
 use actix_web::{
-    HttpResponse, Responder, get, post,
+    FromRequest, HttpRequest, HttpResponse, Responder, delete, dev::Payload, error::InternalError,
+    get, post,
     web::{self},
 };
 use bytes::Bytes;
 use serde_json::json;
 use utoipa::ToSchema;
 
-use super::autofan::CoolboxAutofan;
+use super::autofan::Priority;
+use super::command::{Command, Update};
+use super::control::{FanCurve, FanCurveStatus, PidSettings, PidStatus};
+use super::config::{CachedConfig, ConfigExport, ConfigImportReport};
+use super::discovery::{self, DiscoveredPort};
+use super::framing::{CommandError, MAX_REPLY_TIMEOUT_MS};
+use super::metrics::Metrics;
+use super::registry::{Board, BoardRegistry};
+use super::simulation::{SimulationSettings, SimulationState};
+use super::telemetry::Telemetry;
 
 #[derive(serde::Serialize, serde::Deserialize, ToSchema)]
 struct PlainMessage {
@@ -46,36 +58,119 @@ enum ApiReply {
     Error(String),
 }
 
-fn command_reply_to_response(reply: io::Result<String>) -> HttpResponse {
+/// The board addressed by a request: the one from the `/api/boards/{board_id}/...` path,
+/// or the first registered board for the short `/api/...` endpoints.
+pub struct SelectedBoard(Arc<Board>);
+
+impl FromRequest for SelectedBoard {
+    type Error = actix_web::Error;
+    type Future = std::future::Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let registry = req
+            .app_data::<web::Data<BoardRegistry>>()
+            .expect("The board registry must be registered");
+        // Only the `/api/boards/{board_id}/...` paths have the id
+        let board = match req.match_info().load::<BoardIdParam>() {
+            Ok(BoardIdParam { board_id }) => registry.get(&board_id),
+            Err(..) => registry.default_board(),
+        };
+        std::future::ready(board.map(SelectedBoard).ok_or_else(|| {
+            InternalError::from_response(
+                "Unknown board",
+                HttpResponse::NotFound().json(ApiReply::Error("Unknown board".into())),
+            )
+            .into()
+        }))
+    }
+}
+
+impl Deref for SelectedBoard {
+    type Target = Board;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Path)]
+struct BoardIdParam {
+    /// Board identifier, as listed by `/api/boards`
+    board_id: String,
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CommandParams {
+    /// How long to wait for the device's reply, in milliseconds, at most 60000.
+    /// By default it depends on the command.
+    timeout_ms: Option<u64>,
+}
+
+impl CommandParams {
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout_ms
+            .map(|timeout_ms| Duration::from_millis(timeout_ms.min(MAX_REPLY_TIMEOUT_MS)))
+    }
+}
+
+fn command_reply_to_response(reply: Result<String, CommandError>) -> HttpResponse {
     match reply {
         Ok(text) => HttpResponse::Ok().json(ApiReply::DeviceReply(text)),
-        Err(e) => HttpResponse::InternalServerError().json(ApiReply::Error(e.to_string())),
+        Err(e @ CommandError::Io(..)) => {
+            HttpResponse::InternalServerError().json(ApiReply::Error(e.to_string()))
+        }
+        Err(e @ CommandError::Timeout) => {
+            HttpResponse::GatewayTimeout().json(ApiReply::Error(e.to_string()))
+        }
+        Err(e @ (CommandError::Partial(..) | CommandError::Garbled(..))) => {
+            HttpResponse::BadGateway().json(ApiReply::Error(e.to_string()))
+        }
+        Err(e @ CommandError::QueueFull) => {
+            HttpResponse::ServiceUnavailable().json(ApiReply::Error(e.to_string()))
+        }
     }
 }
 
 #[utoipa::path(
+    params(BoardIdParam, CommandParams),
     responses(
-        (status = 200, description = "Device's reply", body = ApiReply)
+        (status = 200, description = "Device's reply", body = ApiReply),
+        (status = 502, description = "The device's reply is incomplete or garbled", body = ApiReply),
+        (status = 503, description = "Too many commands are waiting for the device", body = ApiReply),
+        (status = 504, description = "The device has not replied in time", body = ApiReply)
     )
 )]
 #[post("/fan-check")]
-async fn fan_check(autofan: web::Data<CoolboxAutofan>) -> impl Responder {
-    const FAN_CHECK_CMD: &[u8] = b"{\"fan_check\":1}";
-    command_reply_to_response(autofan.send_command(FAN_CHECK_CMD))
+async fn fan_check(params: web::Query<CommandParams>, board: SelectedBoard) -> impl Responder {
+    let reply = board
+        .autofan
+        .send_command(&Command::FanCheck, Priority::Low, params.timeout())
+        .await;
+    command_reply_to_response(reply)
 }
 
 #[utoipa::path(
+    params(BoardIdParam, CommandParams),
     responses(
-        (status = 200, description = "Returns diagnostic information", body = ApiReply)
+        (status = 200, description = "Returns diagnostic information", body = ApiReply),
+        (status = 502, description = "The device's reply is incomplete or garbled", body = ApiReply),
+        (status = 503, description = "Too many commands are waiting for the device", body = ApiReply),
+        (status = 504, description = "The device has not replied in time", body = ApiReply)
     )
 )]
 #[post("/diagnostic")]
-async fn diagnostic(autofan: web::Data<CoolboxAutofan>) -> impl Responder {
-    const FAN_CHECK_CMD: &[u8] = b"{\"diagnostic\":1}";
-    command_reply_to_response(autofan.send_command(FAN_CHECK_CMD))
+async fn diagnostic(params: web::Query<CommandParams>, board: SelectedBoard) -> impl Responder {
+    let reply = board
+        .autofan
+        .send_command(&Command::Diagnostic, Priority::Low, params.timeout())
+        .await;
+    command_reply_to_response(reply)
 }
 
 #[utoipa::path(
+    params(BoardIdParam, CommandParams),
     request_body(content = PlainMessage, examples(
         ("Service Mode ON" = (value=json!({"text": "service_mode=1" }))),
         ("Service Mode OFF" = (value=json!({"text": "service_mode=0" }))),
@@ -83,20 +178,28 @@ async fn diagnostic(autofan: web::Data<CoolboxAutofan>) -> impl Responder {
         ("Reset all settings to default" = (value=json!({"text": "default"}))),
     )),
     responses(
-        (status = 200, description = "Device's reply", body = ApiReply)
+        (status = 200, description = "Device's reply", body = ApiReply),
+        (status = 502, description = "The device's reply is incomplete or garbled", body = ApiReply),
+        (status = 503, description = "Too many commands are waiting for the device", body = ApiReply),
+        (status = 504, description = "The device has not replied in time", body = ApiReply)
     )
 )]
 #[post("/message")]
 async fn plain_message(
     message: web::Json<PlainMessage>,
-    autofan: web::Data<CoolboxAutofan>,
+    params: web::Query<CommandParams>,
+    board: SelectedBoard,
 ) -> impl Responder {
-    let message = json!({"message": message.text});
-    let cmd = serde_json::to_string(&message).expect("The message must be valid");
-    command_reply_to_response(autofan.send_command(cmd.as_bytes()))
+    let command = Command::message(message.into_inner().text);
+    let reply = board
+        .autofan
+        .send_command(&command, Priority::Normal, params.timeout())
+        .await;
+    command_reply_to_response(reply)
 }
 
 #[utoipa::path(
+    params(BoardIdParam),
     description = "Updates current GPU temperatures, their desired targets, controls fans and watchdog settings.",
     request_body(content = TempUpdate, examples(
         ("Set manual fan control" = (value=json!({
@@ -122,17 +225,18 @@ async fn plain_message(
     )),
     responses(
         (status = 200, description = "Device's reply", body = ApiReply),
+        (status = 502, description = "The device's reply is incomplete or garbled", body = ApiReply),
+        (status = 503, description = "Too many commands are waiting for the device", body = ApiReply),
+        (status = 504, description = "The device has not replied in time", body = ApiReply),
         (status = 422, description = "Something is wrong with the temperature arrays", body = ApiReply)
     )
 )]
 #[post("/update")]
 async fn update(
     update: web::Json<TempUpdate>,
-    autofan: web::Data<CoolboxAutofan>,
+    board: SelectedBoard,
 ) -> impl Responder {
     let update = update.into_inner();
-    const AUTO_FAN_MODE: i32 = 2;
-    const MANUAL_FAN_MODE: i32 = 1;
     if update.core_temp.len() != update.mem_temp.len()
         && !update.core_temp.is_empty()
         && !update.mem_temp.is_empty()
@@ -141,45 +245,19 @@ async fn update(
             "Both arrays of core and VRAM temps, if provided, must be of the same size".into(),
         ));
     }
-    let mut result_json_chunks = serde_json::Map::<String, serde_json::Value>::new();
-    if !update.core_temp.is_empty() {
-        result_json_chunks.insert("gpu_temp".into(), update.core_temp.into());
-        if update.fan_speed.is_none() {
-            result_json_chunks.insert("fan_mode".into(), AUTO_FAN_MODE.into());
-        }
-    }
-    if !update.mem_temp.is_empty() {
-        result_json_chunks.insert("gpu_mem".into(), update.mem_temp.into());
-        if update.fan_speed.is_none() {
-            result_json_chunks.insert("fan_mode".into(), AUTO_FAN_MODE.into());
-        }
-    }
-    result_json_chunks.insert("target_temp".into(), update.target_core_temp.into());
-    result_json_chunks.insert("target_mem".into(), update.target_mem_temp.into());
-    match update.watchdog_interval {
-        None | Some(0) => {
-            result_json_chunks.insert("watchdog".into(), 0.into());
-        }
-        Some(..) => {
-            result_json_chunks.insert("watchdog".into(), 1.into());
-        }
-    }
-    result_json_chunks.insert("wd_reset_interval".into(), update.watchdog_interval.into());
-    if let Some(fan_speed) = update.fan_speed {
-        result_json_chunks.insert("fan_mode".into(), MANUAL_FAN_MODE.into());
-        result_json_chunks.insert("manual_fan_speed".into(), fan_speed.into());
-    } else {
-        if !result_json_chunks.contains_key("fan_mode") {
-            result_json_chunks.insert("fan_mode".into(), AUTO_FAN_MODE.into());
-        }
-        result_json_chunks.insert("manual_fan_speed".into(), 0.into());
-    }
-    let command_string = serde_json::to_string(&serde_json::Value::from(result_json_chunks))
-        .expect("The object must be serializable");
-    command_reply_to_response(autofan.send_command(command_string.as_bytes()))
+    let command = Update::for_temperatures(
+        update.core_temp,
+        update.mem_temp,
+        update.target_core_temp,
+        update.target_mem_temp,
+        update.watchdog_interval,
+        update.fan_speed,
+    );
+    command_reply_to_response(board.autofan.send_desired_state(command.into()).await)
 }
 
 #[utoipa::path(
+    params(BoardIdParam),
     description = "Checks whether the service is up and running",
     responses(
         (status = 200, description = "Everything is OK."),
@@ -187,36 +265,364 @@ async fn update(
     )
 )]
 #[get("/health")]
-async fn health(autofan: web::Data<CoolboxAutofan>) -> impl Responder {
-    if autofan.is_listener_alive() {
-        HttpResponse::Ok().json(json!({
-            "device": autofan.device_path(),
-            "status": "OK",
-        }))
+async fn health(board: SelectedBoard) -> impl Responder {
+    let health = board_health(&board);
+    if health["status"] == "OK" {
+        HttpResponse::Ok().json(health)
     } else {
-        HttpResponse::InternalServerError().json(json!({
-            "device": autofan.device_path(),
-            "status": "ERROR",
-            "error": "Unable to interact with the device",
-        }))
+        HttpResponse::InternalServerError().json(health)
+    }
+}
+
+fn board_health(board: &Board) -> serde_json::Value {
+    let alive = board.autofan.is_listener_alive();
+    let mut report = json!({
+        "id": board.id,
+        "device": board.autofan.device_path(),
+        "identity": board.autofan.identity(),
+        "status": if alive { "OK" } else { "ERROR" },
+        "connection": board.autofan.connection_status(),
+        "queue": board.autofan.queue_status(),
+        "failsafe": board.autofan.failsafe_status(),
+    });
+    if !alive {
+        report["error"] = json!("Unable to interact with the device");
+    }
+    report
+}
+
+#[utoipa::path(
+    description = "Lists all boards served by this service, along with their health.",
+    responses(
+        (status = 200, description = "The list of boards, in the order they were configured."),
+    )
+)]
+#[get("/boards")]
+async fn list_boards(registry: web::Data<BoardRegistry>) -> impl Responder {
+    let boards = registry
+        .boards()
+        .map(|board| board_health(board))
+        .collect::<Vec<_>>();
+    HttpResponse::Ok().json(boards)
+}
+
+#[utoipa::path(
+    description = "Returns the metrics of all boards in the Prometheus text format: \
+                   the temperatures and the fan settings last sent to the boards, \
+                   the telemetry (in the service mode) and the traffic counters.",
+    responses(
+        (status = 200, description = "The metrics", content_type = "text/plain"),
+    )
+)]
+#[get("/metrics")]
+async fn metrics(registry: web::Data<BoardRegistry>) -> impl Responder {
+    let mut metrics = Metrics::default();
+    for board in registry.boards() {
+        metrics.add_board(board);
     }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics.render())
 }
 
 #[utoipa::path(
-    description = "Streams raw, unfiltered device's output.",
+    params(BoardIdParam),
+    description = "Returns the latest telemetry sample printed by the device. \
+                   The device prints telemetry only while the service mode is on.",
+    responses(
+        (status = 200, description = "The latest telemetry sample", body = Telemetry),
+        (status = 404, description = "No telemetry has been received yet", body = ApiReply),
+    )
+)]
+#[get("/telemetry")]
+async fn telemetry(board: SelectedBoard) -> impl Responder {
+    match board.autofan.latest_telemetry() {
+        Some(telemetry) => HttpResponse::Ok().json(telemetry),
+        None => HttpResponse::NotFound().json(ApiReply::Error(
+            "No telemetry has been received yet. Is the service mode on?".into(),
+        )),
+    }
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ConfigParams {
+    /// Read the configuration from the board even if it's cached.
+    #[serde(default)]
+    refresh: bool,
+}
+
+#[utoipa::path(
+    params(BoardIdParam, ConfigParams),
+    description = "Returns the settings of the board, as reported by `show_config`. \
+                   The settings are cached and read from the board again after any command changing them.",
+    responses(
+        (status = 200, description = "The board's settings", body = CachedConfig),
+        (status = 502, description = "The device's reply is incomplete or garbled", body = ApiReply),
+        (status = 503, description = "Too many commands are waiting for the device", body = ApiReply),
+        (status = 504, description = "The device has not replied in time", body = ApiReply)
+    )
+)]
+#[get("/config")]
+async fn board_config(params: web::Query<ConfigParams>, board: SelectedBoard) -> impl Responder {
+    match board.autofan.config(params.refresh).await {
+        Ok(config) => HttpResponse::Ok().json(config),
+        Err(e) => command_reply_to_response(Err(e)),
+    }
+}
+
+#[utoipa::path(
+    params(BoardIdParam),
+    description = "Reads the settings of the board into a versioned document, \
+                   which can be imported back with `config/import`.",
+    responses(
+        (status = 200, description = "The board's settings", body = ConfigExport),
+        (status = 502, description = "The device's reply is incomplete or garbled", body = ApiReply),
+        (status = 503, description = "Too many commands are waiting for the device", body = ApiReply),
+        (status = 504, description = "The device has not replied in time", body = ApiReply)
+    )
+)]
+#[get("/config/export")]
+async fn export_config(board: SelectedBoard) -> impl Responder {
+    match board.autofan.export_config().await {
+        Ok(export) => HttpResponse::Ok().json(export),
+        Err(e) => command_reply_to_response(Err(e)),
+    }
+}
+
+#[utoipa::path(
+    params(BoardIdParam),
+    description = "Sends the settings from a document made by `config/export` to the board, one message per setting, \
+                   and those taken in updates, like `target_temp`, with the desired state, \
+                   then reads the settings back and compares them with the requested ones.",
+    request_body = ConfigExport,
+    responses(
+        (status = 200, description = "What the board has ended up with", body = ConfigImportReport),
+        (status = 422, description = "Unsupported version of the document, or unknown or invalid settings in it", body = ApiReply),
+        (status = 502, description = "The device's reply is incomplete or garbled", body = ApiReply),
+        (status = 503, description = "Too many commands are waiting for the device", body = ApiReply),
+        (status = 504, description = "The device has not replied in time", body = ApiReply)
+    )
+)]
+#[post("/config/import")]
+async fn import_config(export: web::Json<ConfigExport>, board: SelectedBoard) -> impl Responder {
+    if let Err(e) = export.validate() {
+        return HttpResponse::UnprocessableEntity().json(ApiReply::Error(e));
+    }
+    match board.autofan.import_config(&export).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => command_reply_to_response(Err(e)),
+    }
+}
+
+#[utoipa::path(
+    params(BoardIdParam),
+    description = "Returns the fan curve of the board, if the service controls its fans, \
+                   and the fan speed the curve has chosen last.",
+    responses(
+        (status = 200, description = "The fan curve", body = FanCurveStatus),
+    )
+)]
+#[get("/fan-curve")]
+async fn fan_curve(board: SelectedBoard) -> impl Responder {
+    HttpResponse::Ok().json(board.autofan.fan_curve())
+}
+
+/// Replies with the state of the fan curve once the device has taken the change.
+fn fan_curve_response(board: &Board, result: Result<Option<String>, CommandError>) -> HttpResponse {
+    match result {
+        Ok(..) => HttpResponse::Ok().json(board.autofan.fan_curve()),
+        Err(e) => command_reply_to_response(Err(e)),
+    }
+}
+
+#[utoipa::path(
+    params(BoardIdParam),
+    description = "Makes the service control the fans of the board through a fan curve. \
+                   Whenever the temperatures arrive without a manual fan speed, the curve chooses the speed \
+                   by the hottest GPU core and VRAM, and the board is switched into the manual mode with it. \
+                   The last update is sent again right away with the new curve. \
+                   The curve replaces the PID controller, if there is one.",
+    request_body(content = FanCurve, examples(
+        ("By the cores only" = (value=json!({
+            "core": [{"temp": 40, "speed": 20}, {"temp": 60, "speed": 50}, {"temp": 80, "speed": 100}]
+        }))),
+        ("By the cores and VRAM" = (value=json!({
+            "core": [{"temp": 40, "speed": 20}, {"temp": 80, "speed": 100}],
+            "mem": [{"temp": 70, "speed": 30}, {"temp": 95, "speed": 100}]
+        }))),
+    )),
+    responses(
+        (status = 200, description = "The fan curve", body = FanCurveStatus),
+        (status = 422, description = "The curve is invalid", body = ApiReply),
+        (status = 502, description = "The device's reply is incomplete or garbled", body = ApiReply),
+        (status = 503, description = "Too many commands are waiting for the device", body = ApiReply),
+        (status = 504, description = "The device has not replied in time", body = ApiReply)
+    )
+)]
+#[post("/fan-curve")]
+async fn set_fan_curve(curve: web::Json<FanCurve>, board: SelectedBoard) -> impl Responder {
+    let curve = curve.into_inner();
+    if let Err(e) = curve.validate() {
+        return HttpResponse::UnprocessableEntity().json(ApiReply::Error(e));
+    }
+    let result = board.autofan.set_fan_curve(Some(curve)).await;
+    fan_curve_response(&board, result)
+}
+
+#[utoipa::path(
+    params(BoardIdParam),
+    description = "Removes the fan curve, leaving the fans to the firmware again. \
+                   The last update is sent again right away.",
+    responses(
+        (status = 200, description = "The fan curve", body = FanCurveStatus),
+        (status = 502, description = "The device's reply is incomplete or garbled", body = ApiReply),
+        (status = 503, description = "Too many commands are waiting for the device", body = ApiReply),
+        (status = 504, description = "The device has not replied in time", body = ApiReply)
+    )
+)]
+#[delete("/fan-curve")]
+async fn remove_fan_curve(board: SelectedBoard) -> impl Responder {
+    let result = board.autofan.set_fan_curve(None).await;
+    fan_curve_response(&board, result)
+}
+
+#[utoipa::path(
+    params(BoardIdParam),
+    description = "Returns the settings of the PID controller, if it drives the fans of the board, \
+                   and what it has computed in its last cycle.",
+    responses(
+        (status = 200, description = "The PID controller", body = PidStatus),
+    )
+)]
+#[get("/pid")]
+async fn pid(board: SelectedBoard) -> impl Responder {
+    HttpResponse::Ok().json(board.autofan.pid())
+}
+
+/// Replies with the state of the PID controller once the device has taken the change.
+fn pid_response(board: &Board, result: Result<Option<String>, CommandError>) -> HttpResponse {
+    match result {
+        Ok(..) => HttpResponse::Ok().json(board.autofan.pid()),
+        Err(e) => command_reply_to_response(Err(e)),
+    }
+}
+
+#[utoipa::path(
+    params(BoardIdParam),
+    description = "Makes a PID controller drive the fans of the board. Whenever the temperatures arrive \
+                   without a manual fan speed, the controller chooses the speed to bring the hottest GPU core \
+                   and VRAM to `target_core_temp` and `target_mem_temp`, and the board is switched into \
+                   the manual mode with it. The controller starts afresh, replacing the fan curve, if there is one. \
+                   The settings not given keep their default values.",
+    request_body(content = PidSettings, examples(
+        ("Defaults" = (value=json!({
+            "kp": 4.0, "ki": 0.1, "kd": 0.0, "min_speed": 20, "max_speed": 100, "max_step": 5.0
+        }))),
+        ("Quiet" = (value=json!({"kp": 2.0, "ki": 0.05, "min_speed": 10, "max_speed": 70, "max_step": 1.0}))),
+    )),
+    responses(
+        (status = 200, description = "The PID controller", body = PidStatus),
+        (status = 422, description = "The settings are invalid", body = ApiReply),
+        (status = 502, description = "The device's reply is incomplete or garbled", body = ApiReply),
+        (status = 503, description = "Too many commands are waiting for the device", body = ApiReply),
+        (status = 504, description = "The device has not replied in time", body = ApiReply)
+    )
+)]
+#[post("/pid")]
+async fn set_pid(settings: web::Json<PidSettings>, board: SelectedBoard) -> impl Responder {
+    let settings = settings.into_inner();
+    if let Err(e) = settings.validate() {
+        return HttpResponse::UnprocessableEntity().json(ApiReply::Error(e));
+    }
+    let result = board.autofan.set_pid(Some(settings)).await;
+    pid_response(&board, result)
+}
+
+#[utoipa::path(
+    params(BoardIdParam),
+    description = "Removes the PID controller, leaving the fans to the firmware again. \
+                   The last update is sent again right away.",
+    responses(
+        (status = 200, description = "The PID controller", body = PidStatus),
+        (status = 502, description = "The device's reply is incomplete or garbled", body = ApiReply),
+        (status = 503, description = "Too many commands are waiting for the device", body = ApiReply),
+        (status = 504, description = "The device has not replied in time", body = ApiReply)
+    )
+)]
+#[delete("/pid")]
+async fn remove_pid(board: SelectedBoard) -> impl Responder {
+    let result = board.autofan.set_pid(None).await;
+    pid_response(&board, result)
+}
+
+fn no_simulation_response() -> HttpResponse {
+    HttpResponse::NotFound().json(ApiReply::Error(
+        "The board is real, there is no simulation. Run the service with --dummy".into(),
+    ))
+}
+
+#[utoipa::path(
+    params(BoardIdParam),
+    description = "Returns the state of the rig simulated in the dummy mode. \
+                   Its temperatures can be fed back through `update` to try the fan control end to end.",
+    responses(
+        (status = 200, description = "The simulated rig", body = SimulationState),
+        (status = 404, description = "The board is real", body = ApiReply),
+    )
+)]
+#[get("/simulation")]
+async fn simulation(board: SelectedBoard) -> impl Responder {
+    match board.autofan.simulation() {
+        Some(plant) => HttpResponse::Ok().json(plant.lock().unwrap().state()),
+        None => no_simulation_response(),
+    }
+}
+
+#[utoipa::path(
+    params(BoardIdParam),
+    description = "Changes the parameters of the rig simulated in the dummy mode.",
+    request_body(content = SimulationSettings, examples(
+        ("Heavy load on a hot day" = (value=json!({"gpus": 2, "ambient_temp": 35.0, "heat_load": 300.0}))),
+        ("Idle" = (value=json!({"gpus": 2, "ambient_temp": 25.0, "heat_load": 30.0}))),
+    )),
+    responses(
+        (status = 200, description = "The simulated rig", body = SimulationState),
+        (status = 404, description = "The board is real", body = ApiReply),
+    )
+)]
+#[post("/simulation")]
+async fn configure_simulation(
+    settings: web::Json<SimulationSettings>,
+    board: SelectedBoard,
+) -> impl Responder {
+    match board.autofan.simulation() {
+        Some(plant) => {
+            let mut plant = plant.lock().unwrap();
+            plant.configure(settings.into_inner());
+            HttpResponse::Ok().json(plant.state())
+        }
+        None => no_simulation_response(),
+    }
+}
+
+#[utoipa::path(
+    params(BoardIdParam),
+    description = "Streams the device's output: telemetry and anything else the device prints on its own. \
+                   Replies to commands are not included.",
     responses(
         (status = 200, description = "Constantly appending text streamed from the device.")
     )
 )]
 #[get("/watch")]
-async fn watch(autofan: web::Data<CoolboxAutofan>) -> impl Responder {
+async fn watch(board: SelectedBoard) -> impl Responder {
     // This code receives broadcasts from the device listener and re-transmits it into
     // a channel, that acts as an asynchronous response stream.
     let (mut response_sender, output_stream) =
         futures::channel::mpsc::channel::<io::Result<Bytes>>(5);
     // Because the broadcasts come from a synchronous channel,
     // a dedicated thread is neccessary for the re-transmission.
-    let mut receiver = autofan.subscribe();
+    let mut receiver = board.autofan.subscribe();
     std::thread::spawn(move || {
         loop {
             // Receiving broadcasts from the device listener/reader,
@@ -249,3 +655,48 @@ async fn watch(autofan: web::Data<CoolboxAutofan>) -> impl Responder {
         .insert_header(actix_web::http::header::ContentEncoding::Identity)
         .streaming(output_stream)
 }
+
+#[utoipa::path(
+    description = "Looks for Coolbox boards among the USB serial ports. \
+                   Every port not yet used by this service is opened and probed with a harmless command, \
+                   which takes a couple of seconds.",
+    responses(
+        (status = 200, description = "All probed ports", body = Vec<DiscoveredPort>),
+        (status = 500, description = "Unable to list serial ports", body = ApiReply),
+    )
+)]
+#[get("/discover")]
+async fn discover(registry: web::Data<BoardRegistry>) -> impl Responder {
+    let managed_ports = registry
+        .boards()
+        .filter_map(|board| Some((board.autofan.device_path()?, board.id.clone())))
+        .collect::<Vec<_>>();
+    match web::block(move || discovery::discover(&managed_ports)).await {
+        Ok(Ok(ports)) => HttpResponse::Ok().json(ports),
+        Ok(Err(e)) => HttpResponse::InternalServerError().json(ApiReply::Error(e.to_string())),
+        Err(e) => HttpResponse::InternalServerError().json(ApiReply::Error(e.to_string())),
+    }
+}
+
+/// Registers the endpoints dealing with a single board.
+pub fn board_services(config: &mut utoipa_actix_web::service_config::ServiceConfig) {
+    config
+        .service(health)
+        .service(fan_check)
+        .service(plain_message)
+        .service(update)
+        .service(diagnostic)
+        .service(telemetry)
+        .service(board_config)
+        .service(export_config)
+        .service(import_config)
+        .service(fan_curve)
+        .service(set_fan_curve)
+        .service(remove_fan_curve)
+        .service(pid)
+        .service(set_pid)
+        .service(remove_pid)
+        .service(simulation)
+        .service(configure_simulation)
+        .service(watch);
+}
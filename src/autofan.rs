@@ -1,13 +1,43 @@
+use std::collections::VecDeque;
 use std::io::{self, Read, Write};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use bus::Bus;
+use futures::channel::oneshot;
 use serialport::{SerialPort, TTYPort};
 
+use crate::capture::{self, CaptureRecorder, Direction};
+use crate::command::{Command, FanMode, Update};
+use crate::control::{
+    FanControl, FanCurve, FanCurveStatus, PidController, PidSettings, PidStatus,
+};
+use crate::config::{
+    BoardConfig, CONFIG_EXPORT_VERSION, CachedConfig, ConfigExport, ConfigImportReport,
+    ImportedSetting, UPDATE_KEYS,
+};
+use crate::emulator;
+use crate::failsafe::{Failsafe, FailsafeSettings, FailsafeStatus, failsafe_command};
+use crate::framing::{CommandError, ReplySpec};
+use crate::identity::{BoardIdentity, PortSelector};
+use crate::simulation::{SharedThermalPlant, SimulationSettings, ThermalPlant};
+use crate::state::StateFile;
+use crate::telemetry::{OutputRouter, RoutedOutput, Telemetry, TelemetryParser, unix_time_now};
+
 pub const READ_TIMEOUT_MS: u64 = 500;
 pub const POST_CONNECTION_TIMEOUT_MS: u64 = 800;
+pub const SUPERVISOR_POLL_INTERVAL_MS: u64 = 100;
+pub const RECONNECT_MIN_BACKOFF_MS: u64 = 1000;
+pub const RECONNECT_MAX_BACKOFF_MS: u64 = 30_000;
+/// How many commands may wait for their turn. When the queue is full, a new command
+/// takes the place of a less urgent one, or gets rejected.
+pub const COMMAND_QUEUE_CAPACITY: usize = 16;
+/// Targets sent along with the temperatures until the desired state has some.
+pub const DEFAULT_TARGET_TEMP: i32 = 60;
+pub const DEFAULT_TARGET_MEM: i32 = 90;
 
 pub fn open_coolbox_autofan_port(device_path: &str) -> Result<TTYPort, serialport::Error> {
     // The CoolBox board uses 9600 baud, 8N1, no flow control.
@@ -24,15 +54,271 @@ pub fn open_coolbox_autofan_port(device_path: &str) -> Result<TTYPort, serialpor
     port
 }
 
-pub struct CoolboxAutofan {
-    #[allow(dead_code)]
-    tty_port_path: Option<String>,
-    tty_port_and_receiver: Mutex<(Box<dyn SerialPort>, std::sync::mpsc::Receiver<String>)>,
-    listening_handle: std::thread::JoinHandle<io::Result<()>>,
+/// Counters of the traffic with the device, exported as metrics.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct TrafficStatus {
+    /// Commands written to the device.
+    pub commands_sent: u64,
+    /// Commands the device hasn't replied to in time, fully or at all.
+    pub command_timeouts: u64,
+    /// How many times the listener has been started again on a reopened port.
+    pub listener_restarts: u64,
+    /// Bytes read from the serial port.
+    pub bytes_read: u64,
+}
+
+/// The counters behind [`TrafficStatus`]. Atomic, as the listener bumps them for every byte.
+#[derive(Debug, Default)]
+struct TrafficCounters {
+    commands_sent: AtomicU64,
+    command_timeouts: AtomicU64,
+    listener_restarts: AtomicU64,
+    bytes_read: AtomicU64,
+}
+
+impl TrafficCounters {
+    fn status(&self) -> TrafficStatus {
+        TrafficStatus {
+            commands_sent: self.commands_sent.load(Ordering::Relaxed),
+            command_timeouts: self.command_timeouts.load(Ordering::Relaxed),
+            listener_restarts: self.listener_restarts.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Reconnection statistics, reported by the health endpoint.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ConnectionStatus {
+    /// How many times the service has tried to reopen the port after losing the device.
+    pub reconnect_attempts: u64,
+    /// How many of those attempts were successful.
+    pub reconnects: u64,
+    /// The error that has made the service lose the device the last time.
+    pub last_disconnect_error: Option<String>,
+    /// The error of the last unsuccessful reconnection attempt.
+    pub last_reconnect_error: Option<String>,
+    /// When the device has been reconnected the last time, in seconds since the Unix epoch.
+    pub last_reconnected_at: Option<u64>,
+}
+
+/// Command queue statistics, reported by the health endpoint.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct QueueStatus {
+    /// Commands waiting for their turn right now.
+    pub depth: usize,
+    /// How many commands may wait at once.
+    pub capacity: usize,
+    /// Commands sent to the device so far.
+    pub executed: u64,
+    /// Commands rejected, or pushed out by more urgent ones, because the queue was full.
+    pub rejected: u64,
+    /// Commands dropped before reaching the device, because nobody was waiting for them anymore.
+    pub cancelled: u64,
+    /// How long the last command has waited for its turn, in milliseconds.
+    pub last_wait_ms: u64,
+    /// The longest wait so far, in milliseconds.
+    pub max_wait_ms: u64,
+    /// The average wait, in milliseconds.
+    pub average_wait_ms: u64,
+}
+
+/// How urgent a command is. More urgent commands jump ahead of the less urgent ones in the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Diagnostics and checks, which can wait.
+    Low,
+    /// Everything else.
+    Normal,
+    /// Safety-critical updates of temperatures and targets.
+    High,
+}
+
+type CommandReplySender = oneshot::Sender<Result<String, CommandError>>;
+
+/// A command waiting for its turn to be sent to the device.
+/// Gets cancelled once the receiving end of its reply sender is dropped.
+struct QueuedCommand {
+    cmd: Vec<u8>,
+    changes_settings: bool,
+    /// The settings of the command, if it's an update.
+    update_settings: Option<Update>,
+    priority: Priority,
+    reply_spec: ReplySpec,
+    queued_at: Instant,
+    reply_sender: CommandReplySender,
+}
+
+#[derive(Default)]
+struct QueueState {
+    pending: VecDeque<QueuedCommand>,
+    closed: bool,
+    executed: u64,
+    rejected: u64,
+    cancelled: u64,
+    last_wait: Duration,
+    max_wait: Duration,
+    total_wait: Duration,
+}
+
+/// A bounded queue of the commands for the device, served by [`commanding_thread`].
+struct CommandQueue {
+    state: Mutex<QueueState>,
+    available: Condvar,
+    capacity: usize,
+}
+
+impl QueueState {
+    /// Forgets the commands nobody is waiting for, e.g. because the HTTP client has disconnected.
+    fn drop_cancelled(&mut self) {
+        let before = self.pending.len();
+        self.pending.retain(|queued| !queued.reply_sender.is_canceled());
+        self.cancelled += (before - self.pending.len()) as u64;
+    }
+}
+
+impl CommandQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(QueueState::default()),
+            available: Condvar::new(),
+            capacity,
+        }
+    }
+
+    /// Puts the command after all the commands of the same or higher priority.
+    /// If the queue is full, the last of the less urgent commands gets pushed out,
+    /// and if there are none, the command gets rejected.
+    fn push(&self, command: QueuedCommand) -> Result<(), CommandError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(CommandError::Io(io::Error::other("The board is being shut down")));
+        }
+        state.drop_cancelled();
+        if state.pending.len() >= self.capacity {
+            let can_push_out = state
+                .pending
+                .back()
+                .filter(|queued| queued.priority < command.priority)
+                .is_some();
+            state.rejected += 1;
+            if !can_push_out {
+                return Err(CommandError::QueueFull);
+            }
+            if let Some(pushed_out) = state.pending.pop_back() {
+                pushed_out.reply_sender.send(Err(CommandError::QueueFull)).ok();
+            }
+        }
+        let position = state
+            .pending
+            .iter()
+            .position(|queued| queued.priority < command.priority)
+            .unwrap_or(state.pending.len());
+        state.pending.insert(position, command);
+        self.available.notify_one();
+        Ok(())
+    }
+
+    /// Waits for the next command. Returns `None` once the queue is closed.
+    fn pop(&self) -> Option<QueuedCommand> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return None;
+            }
+            state.drop_cancelled();
+            if let Some(command) = state.pending.pop_front() {
+                let wait = command.queued_at.elapsed();
+                state.executed += 1;
+                state.last_wait = wait;
+                state.max_wait = state.max_wait.max(wait);
+                state.total_wait += wait;
+                return Some(command);
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    /// Stops serving the queue. The commands still waiting get dropped,
+    /// and their senders get an error.
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.pending.clear();
+        self.available.notify_all();
+    }
+
+    fn status(&self) -> QueueStatus {
+        let state = self.state.lock().unwrap();
+        QueueStatus {
+            depth: state.pending.len(),
+            capacity: self.capacity,
+            executed: state.executed,
+            rejected: state.rejected,
+            cancelled: state.cancelled,
+            last_wait_ms: state.last_wait.as_millis() as u64,
+            max_wait_ms: state.max_wait.as_millis() as u64,
+            average_wait_ms: state
+                .total_wait
+                .as_millis()
+                .checked_div(state.executed as u128)
+                .unwrap_or_default() as u64,
+        }
+    }
+}
+
+/// The board's configuration, as read the last time.
+#[derive(Default)]
+struct ConfigCache {
+    cached: Option<CachedConfig>,
+    /// Changes whenever the cached configuration becomes outdated,
+    /// so a configuration read meanwhile wouldn't get cached.
+    generation: u64,
+}
+
+impl ConfigCache {
+    fn invalidate(&mut self) {
+        self.cached = None;
+        self.generation += 1;
+    }
+}
+
+/// The state shared between the `CoolboxAutofan` and its background threads.
+#[derive(Clone)]
+struct SharedState {
+    queue: Arc<CommandQueue>,
+    /// Hands a reopened port over to the commanding thread.
+    port_sender: mpsc::Sender<Box<dyn SerialPort>>,
+    response_sender: mpsc::Sender<u8>,
+    listening_handle: Arc<Mutex<Option<std::thread::JoinHandle<io::Result<()>>>>>,
     listening_exit_flag: Arc<AtomicBool>,
     command_started_flag: Arc<AtomicBool>,
-    command_delivered_flag: Arc<AtomicBool>,
     stream_bus: Arc<Mutex<Bus<Vec<u8>>>>,
+    latest_telemetry: Arc<Mutex<Option<Telemetry>>>,
+    desired_state: Arc<Mutex<Option<Command>>>,
+    /// The last command sent for the desired state, as changed by the fan control or the fail-safe.
+    applied_state: Arc<Mutex<Option<Command>>>,
+    /// Where the desired state is persisted, if anywhere.
+    state_file: Arc<Mutex<Option<StateFile>>>,
+    fan_control: Arc<Mutex<FanControl>>,
+    failsafe: Arc<Mutex<Failsafe>>,
+    config_cache: Arc<Mutex<ConfigCache>>,
+    /// The settings of the last update executed, unless the settings have been changed otherwise.
+    last_update_settings: Arc<Mutex<Option<Update>>>,
+    connection_status: Arc<Mutex<ConnectionStatus>>,
+    traffic: Arc<TrafficCounters>,
+    identity: Arc<Mutex<Option<BoardIdentity>>>,
+    capture: Option<Arc<CaptureRecorder>>,
+}
+
+pub struct CoolboxAutofan {
+    shared: SharedState,
+    /// The supervising (if any), fail-safe and commanding threads, in the order of joining.
+    handles: Mutex<Vec<std::thread::JoinHandle<()>>>,
+    simulation: Option<SharedThermalPlant>,
 }
 
 /// Constantly listens for any messages from the Coolbox Autofan Board (CAB).
@@ -40,14 +326,7 @@ pub struct CoolboxAutofan {
 /// it forgets its last instructions. For instance, if it was ordered to maintain a certain temperature
 /// target, once you disconnected it can switch back to manual mode and default speed/temperature settings.
 /// So the port has to be always open, ready for reading.
-fn listening_thread(
-    mut port: Box<dyn SerialPort>,
-    exit_flag: Arc<AtomicBool>,
-    is_command_started: Arc<AtomicBool>,
-    is_command_delivered: Arc<AtomicBool>,
-    response_sender: std::sync::mpsc::SyncSender<String>,
-    stream_bus: Arc<Mutex<Bus<Vec<u8>>>>,
-) -> io::Result<()> {
+fn listening_thread(mut port: Box<dyn SerialPort>, shared: SharedState) -> io::Result<()> {
     fn dump_broadcast_buffer(
         stream_bus: &Arc<Mutex<Bus<Vec<u8>>>>,
         broadcast_buffer: &mut Vec<u8>,
@@ -58,22 +337,20 @@ fn listening_thread(
         }
     }
 
-    fn dump_command_buffer(
-        command_started_flag: &Arc<AtomicBool>,
-        is_command_delivered: &Arc<AtomicBool>,
-        response_sender: &std::sync::mpsc::SyncSender<String>,
-        command_buffer: &mut Vec<u8>,
-    ) {
-        let response = String::from_utf8_lossy(&*command_buffer).to_string();
-        response_sender.send(response.clone()).ok();
-        command_buffer.clear();
-        command_started_flag.store(false, Ordering::Relaxed);
-        is_command_delivered.store(false, Ordering::Relaxed);
-    }
-
+    let SharedState {
+        listening_exit_flag: exit_flag,
+        command_started_flag: is_command_started,
+        response_sender,
+        stream_bus,
+        latest_telemetry,
+        traffic,
+        capture,
+        ..
+    } = shared;
     let mut device_buffer: [u8; 1] = [0; 1];
     let mut broadcast_buffer = Vec::<u8>::new();
-    let mut command_buffer = Vec::<u8>::new();
+    let mut telemetry_parser = TelemetryParser::default();
+    let mut output_router = OutputRouter::default();
     loop {
         if exit_flag.load(Ordering::Relaxed) {
             log::info!("Stopping listener as requested");
@@ -81,37 +358,41 @@ fn listening_thread(
         }
         match port.read(&mut device_buffer) {
             Ok(bytes) => {
-                broadcast_buffer.extend_from_slice(&device_buffer);
                 if bytes == 0 {
                     // EOF. Time to dump whatever we've read so far
                     dump_broadcast_buffer(&stream_bus, &mut broadcast_buffer);
+                    continue;
                 }
-                if is_command_started.load(Ordering::Relaxed) {
-                    if bytes == 1 {
-                        command_buffer.extend_from_slice(&device_buffer);
-                    } else if is_command_delivered.load(Ordering::Relaxed) {
-                        // EOF. Time to dump whatever we've read so far
-                        dump_command_buffer(
-                            &is_command_started,
-                            &is_command_delivered,
-                            &response_sender,
-                            &mut command_buffer,
-                        );
+                traffic.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+                if let Some(capture) = &capture {
+                    capture.record(Direction::Rx, &device_buffer);
+                }
+                match output_router.feed_byte(device_buffer[0]) {
+                    Some(RoutedOutput::Telemetry(line)) => {
+                        if let Some(telemetry) =
+                            telemetry_parser.feed_line(&String::from_utf8_lossy(&line))
+                            && let Ok(mut latest) = latest_telemetry.lock()
+                        {
+                            *latest = Some(telemetry);
+                        }
+                        broadcast_buffer.extend_from_slice(&line);
+                    }
+                    Some(RoutedOutput::Other(output)) => {
+                        if is_command_started.load(Ordering::Relaxed) {
+                            // The command's sender is waiting for this, and knows when the reply is over
+                            for byte in output {
+                                response_sender.send(byte).ok();
+                            }
+                        } else {
+                            // Something the device has said on its own
+                            broadcast_buffer.extend_from_slice(&output);
+                        }
                     }
+                    None => {}
                 }
             }
             Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
-                // Timeout is also a sign we can dump whatever we've read so far if we have some
-                if is_command_started.load(Ordering::Relaxed)
-                    && is_command_delivered.load(Ordering::Relaxed)
-                {
-                    dump_command_buffer(
-                        &is_command_started,
-                        &is_command_delivered,
-                        &response_sender,
-                        &mut command_buffer,
-                    );
-                }
+                // Timeout is a sign we can dump whatever we've read so far if we have some
                 dump_broadcast_buffer(&stream_bus, &mut broadcast_buffer);
             }
             Err(e) => {
@@ -122,123 +403,1178 @@ fn listening_thread(
     }
 }
 
+/// Watches the listening thread and, if it dies (usually because the USB cable has been unplugged
+/// or the adapter has been reset), reopens the port and replays the last desired state of the board.
+/// The port is looked up again every time, because the board may come back under a different name.
+fn supervising_thread(port_selector: PortSelector, shared: SharedState) {
+    let exit_requested = || shared.listening_exit_flag.load(Ordering::Relaxed);
+    let sleep_unless_exiting = |duration: Duration| {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline && !exit_requested() {
+            std::thread::sleep(Duration::from_millis(SUPERVISOR_POLL_INTERVAL_MS));
+        }
+    };
+    loop {
+        sleep_unless_exiting(Duration::from_millis(SUPERVISOR_POLL_INTERVAL_MS));
+        if exit_requested() {
+            return;
+        }
+        let finished_handle = {
+            let mut handle = shared.listening_handle.lock().unwrap();
+            match handle.as_ref() {
+                Some(h) if h.is_finished() => handle.take(),
+                _ => None,
+            }
+        };
+        let Some(finished_handle) = finished_handle else {
+            continue;
+        };
+        let disconnect_error = match finished_handle.join() {
+            Ok(Ok(())) => "Listening thread has stopped".to_string(),
+            Ok(Err(e)) => e.to_string(),
+            Err(..) => "Listening thread has panicked".to_string(),
+        };
+        log::warn!("Lost the device {}: {}", port_selector, disconnect_error);
+        shared.connection_status.lock().unwrap().last_disconnect_error = Some(disconnect_error);
+
+        let mut backoff = Duration::from_millis(RECONNECT_MIN_BACKOFF_MS);
+        loop {
+            if exit_requested() {
+                return;
+            }
+            shared.connection_status.lock().unwrap().reconnect_attempts += 1;
+            match reopen_port(&port_selector, &shared) {
+                Ok(tty_port_path) => {
+                    log::info!("Reconnected to the device {} at {}", port_selector, tty_port_path);
+                    shared.traffic.listener_restarts.fetch_add(1, Ordering::Relaxed);
+                    let mut status = shared.connection_status.lock().unwrap();
+                    status.reconnects += 1;
+                    status.last_reconnect_error = None;
+                    status.last_reconnected_at = Some(unix_time_now());
+                    break;
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Unable to reconnect to {}: {}. Next attempt in {:?}",
+                        port_selector,
+                        e,
+                        backoff
+                    );
+                    shared.connection_status.lock().unwrap().last_reconnect_error =
+                        Some(e.to_string());
+                    sleep_unless_exiting(backoff);
+                    backoff = (backoff * 2).min(Duration::from_millis(RECONNECT_MAX_BACKOFF_MS));
+                }
+            }
+        }
+
+        // The board has forgotten everything it's been told, so reminding it.
+        shared.replay_desired_state();
+    }
+}
+
+/// Switches the board to the safe fan speed whenever the GPU temperatures stop coming
+/// for too long, see [`crate::failsafe`].
+fn failsafe_thread(shared: SharedState) {
+    while !shared.listening_exit_flag.load(Ordering::Relaxed) {
+        std::thread::sleep(Duration::from_millis(SUPERVISOR_POLL_INTERVAL_MS));
+        let speed = {
+            let mut failsafe = shared.failsafe.lock().unwrap();
+            if !failsafe.check() {
+                continue;
+            }
+            failsafe.speed()
+        };
+        let Some(speed) = speed else {
+            continue;
+        };
+        let desired_state = shared.desired_state.lock().unwrap().clone();
+        let command = failsafe_command(desired_state.as_ref(), speed);
+        *shared.applied_state.lock().unwrap() = Some(command.clone());
+        let reply_spec = command.reply_spec();
+        match futures::executor::block_on(shared.execute(&command, Priority::High, reply_spec)) {
+            Ok(reply) => log::info!("Engaged the fail-safe, the device replied {:?}", reply),
+            Err(e) => log::error!("Unable to engage the fail-safe: {}", e),
+        }
+    }
+}
+
+fn reopen_port(port_selector: &PortSelector, shared: &SharedState) -> io::Result<String> {
+    let tty_port_path = port_selector.resolve()?;
+    let tty_port = open_coolbox_autofan_port(&tty_port_path)?;
+    let listening_port_clone = tty_port.try_clone()?;
+    shared
+        .port_sender
+        .send(Box::new(tty_port))
+        .map_err(|_| io::Error::other("The commanding thread has stopped"))?;
+    *shared.identity.lock().unwrap() = Some(BoardIdentity::of_port(&tty_port_path));
+    // The board has reset its settings
+    shared.config_cache.lock().unwrap().invalidate();
+    *shared.last_update_settings.lock().unwrap() = None;
+    shared.spawn_listener(listening_port_clone);
+    Ok(tty_port_path)
+}
+
+/// Owns the writing end of the port and executes the queued commands one by one,
+/// so the API handlers only have to await the replies, never blocking on the device.
+/// Whenever the device gets reconnected, the supervisor hands the new port over.
+fn commanding_thread(
+    mut writing_port: Box<dyn SerialPort>,
+    response_receiver: mpsc::Receiver<u8>,
+    port_receiver: mpsc::Receiver<Box<dyn SerialPort>>,
+    shared: SharedState,
+) {
+    while let Some(command) = shared.queue.pop() {
+        while let Ok(reopened_port) = port_receiver.try_recv() {
+            writing_port = reopened_port;
+        }
+        // Whatever is left from a reply that has come too late
+        while response_receiver.try_recv().is_ok() {}
+        // Just before writing anything, we set a flag "command is executing".
+        // This flag means that all the following bytes coming from the device must be
+        // interpreted as a command's reply, and passed here until the reply is complete.
+        shared.command_started_flag.store(true, Ordering::Relaxed);
+        let result = shared.exchange(
+            &mut writing_port,
+            &response_receiver,
+            &command.cmd,
+            &command.reply_spec,
+        );
+        shared.command_started_flag.store(false, Ordering::Relaxed);
+        if shared.settings_changed(&command) {
+            shared.config_cache.lock().unwrap().invalidate();
+        }
+        if let Err(CommandError::Timeout | CommandError::Partial(..)) = &result {
+            shared.traffic.command_timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Err(e) = &result {
+            log::error!("Command {:?} has failed: {}", String::from_utf8_lossy(&command.cmd), e);
+        }
+        // Nobody may be waiting anymore, which is fine
+        command.reply_sender.send(result).ok();
+    }
+    log::info!("Stopping the command execution as requested");
+}
+
+impl SharedState {
+    fn spawn_listener(&self, reading_port: Box<dyn SerialPort>) {
+        let shared = self.clone();
+        let handle = std::thread::spawn(move || -> Result<(), io::Error> {
+            listening_thread(reading_port, shared)
+        });
+        *self.listening_handle.lock().unwrap() = Some(handle);
+    }
+
+    /// Whether the executed command may have changed the settings reported by `show_config`.
+    /// An update with the same settings as the update before doesn't, so the regular updates
+    /// with fresh temperatures keep the cached configuration.
+    fn settings_changed(&self, command: &QueuedCommand) -> bool {
+        if !command.changes_settings {
+            return false;
+        }
+        let mut last_update_settings = self.last_update_settings.lock().unwrap();
+        match &command.update_settings {
+            Some(settings) => {
+                last_update_settings.replace(settings.clone()).as_ref() != Some(settings)
+            }
+            None => {
+                *last_update_settings = None;
+                true
+            }
+        }
+    }
+
+    /// Queues a command and waits for the commanding thread to execute it.
+    /// Dropping the future cancels the command, unless it's been sent to the device already.
+    async fn execute(
+        &self,
+        command: &Command,
+        priority: Priority,
+        reply_spec: ReplySpec,
+    ) -> Result<String, CommandError> {
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        self.queue.push(QueuedCommand {
+            cmd: command.encode(),
+            changes_settings: command.changes_settings(),
+            update_settings: match command {
+                Command::Update(update) => Some(update.settings()),
+                _ => None,
+            },
+            priority,
+            reply_spec,
+            queued_at: Instant::now(),
+            reply_sender,
+        })?;
+        reply_receiver.await.unwrap_or_else(|_| {
+            Err(CommandError::Io(io::Error::other(
+                "The command has been dropped, the board is being shut down",
+            )))
+        })
+    }
+
+    /// The command to send to the device for the desired state: with a fan curve
+    /// or a PID controller set, it chooses the fan speed instead of the firmware's auto mode.
+    /// While the fail-safe is engaged, the safe fan speed is sent instead.
+    /// The result is remembered as the applied state.
+    fn controlled(&self, command: &Command) -> Command {
+        let failsafe_speed = self.failsafe.lock().unwrap().speed();
+        let command = match failsafe_speed {
+            Some(speed) => failsafe_command(Some(command), speed),
+            None => self.fan_control.lock().unwrap().apply(command),
+        };
+        *self.applied_state.lock().unwrap() = Some(command.clone());
+        command
+    }
+
+    /// Sends the desired state to the device again, if there is one, and waits for the reply.
+    fn replay_desired_state(&self) {
+        let desired_state = self.desired_state.lock().unwrap().clone();
+        if let Some(command) = desired_state {
+            let command = self.controlled(&command);
+            let reply_spec = command.reply_spec();
+            match futures::executor::block_on(self.execute(&command, Priority::High, reply_spec)) {
+                Ok(reply) => {
+                    log::info!("Replayed the desired state, the device replied {:?}", reply)
+                }
+                Err(e) => log::error!("Unable to replay the desired state: {}", e),
+            }
+        }
+    }
+
+    fn exchange(
+        &self,
+        writing_port: &mut Box<dyn SerialPort>,
+        response_receiver: &mpsc::Receiver<u8>,
+        cmd: &[u8],
+        reply_spec: &ReplySpec,
+    ) -> Result<String, CommandError> {
+        writing_port.write_all(cmd)?;
+        self.traffic.commands_sent.fetch_add(1, Ordering::Relaxed);
+        if let Some(capture) = &self.capture {
+            capture.record(Direction::Tx, cmd);
+        }
+        log::debug!("Delivered command: {:?}", String::from_utf8_lossy(cmd));
+        let deadline = Instant::now() + reply_spec.timeout;
+        let quiet_period = reply_spec.quiet_period();
+        let mut framer = reply_spec.framer();
+        loop {
+            let time_left = deadline.saturating_duration_since(Instant::now());
+            let quiet_before_deadline = quiet_period.filter(|&quiet| quiet <= time_left);
+            match response_receiver.recv_timeout(quiet_before_deadline.unwrap_or(time_left)) {
+                Ok(byte) => {
+                    if framer.feed_byte(byte) {
+                        return reply_spec.decode(framer.into_reply());
+                    }
+                }
+                // The board has been quiet long enough, which ends an unacknowledged command
+                Err(..) if quiet_before_deadline.is_some() => {
+                    return reply_spec.decode(framer.into_reply());
+                }
+                Err(..) if framer.is_empty() => return Err(CommandError::Timeout),
+                Err(..) => {
+                    return Err(CommandError::Partial(
+                        String::from_utf8_lossy(&framer.into_reply()).to_string(),
+                    ));
+                }
+            }
+        }
+    }
+}
+
 impl TryFrom<String> for CoolboxAutofan {
     type Error = serialport::Error;
 
     fn try_from(tty_port_path: String) -> Result<Self, Self::Error> {
-        let tty_port = open_coolbox_autofan_port(&tty_port_path)?;
-        let listening_port_clone = tty_port.try_clone()?;
-        Ok(Self::from_ports(
-            Box::new(tty_port),
-            listening_port_clone,
-            Some(tty_port_path),
-        ))
+        Self::open(PortSelector::Path(tty_port_path), None)
     }
 }
 
 impl CoolboxAutofan {
-    #[allow(dead_code)]
-    pub fn join(self) -> io::Result<()> {
-        self.listening_exit_flag.store(true, Ordering::Relaxed);
-        match self.listening_handle.join() {
-            Ok(result) => result,
-            Err(..) => Err(io::Error::new(
+    /// Stops all the threads talking to the board and waits for them to finish.
+    /// The commands still queued are dropped.
+    pub fn join(&self) -> io::Result<()> {
+        self.shared.listening_exit_flag.store(true, Ordering::Relaxed);
+        self.shared.queue.close();
+        let handles = std::mem::take(&mut *self.handles.lock().unwrap());
+        for handle in handles {
+            handle.join().ok();
+        }
+        let listening_handle = self.shared.listening_handle.lock().unwrap().take();
+        match listening_handle.map(|handle| handle.join()) {
+            None => Ok(()),
+            Some(Ok(result)) => result,
+            Some(Err(..)) => Err(io::Error::new(
                 io::ErrorKind::Interrupted,
                 "Listening thread has panicked",
             )),
         }
     }
 
-    pub fn dummy() -> Result<Self, serialport::Error> {
-        let (tty_port, listening_port_clone) = TTYPort::pair()?;
-        let listening_port_clone = Box::new(listening_port_clone);
+    /// Creates a controller talking to an emulated board instead of a real one.
+    /// The board cools a simulated rig with the given parameters.
+    pub fn dummy(
+        simulation: SimulationSettings,
+        capture: Option<CaptureRecorder>,
+    ) -> Result<Self, serialport::Error> {
+        let (device_port, mut tty_port) = TTYPort::pair()?;
+        tty_port.set_timeout(Duration::from_millis(READ_TIMEOUT_MS))?;
+        let plant = Arc::new(Mutex::new(ThermalPlant::new(simulation)));
+        emulator::spawn_emulator(device_port, Arc::clone(&plant));
+        let listening_port_clone = tty_port.try_clone()?;
+        Ok(Self {
+            simulation: Some(plant),
+            ..Self::from_ports(Box::new(tty_port), listening_port_clone, None, capture)
+        })
+    }
+
+    /// Creates a controller talking to a recorded session instead of a real board,
+    /// see [`crate::capture`] for the format of the capture.
+    pub fn replay(capture_path: &Path) -> Result<Self, serialport::Error> {
+        let records = capture::read_capture(capture_path)?;
+        let (device_port, mut tty_port) = TTYPort::pair()?;
+        tty_port.set_timeout(Duration::from_millis(READ_TIMEOUT_MS))?;
+        capture::spawn_replay(device_port, records);
+        let listening_port_clone = tty_port.try_clone()?;
         Ok(Self::from_ports(
             Box::new(tty_port),
             listening_port_clone,
             None,
+            None,
+        ))
+    }
+
+    /// Finds the board's port and connects to it. If the connection gets lost,
+    /// the port is looked up again through the same selector.
+    pub fn open(
+        port_selector: PortSelector,
+        capture: Option<CaptureRecorder>,
+    ) -> Result<Self, serialport::Error> {
+        let tty_port_path = port_selector.resolve()?;
+        let tty_port = open_coolbox_autofan_port(&tty_port_path)?;
+        let listening_port_clone = tty_port.try_clone()?;
+        Ok(Self::from_selected_ports(
+            Box::new(tty_port),
+            listening_port_clone,
+            Some(port_selector),
+            Some(tty_port_path),
+            capture,
         ))
     }
 
+    /// Creates the controller on top of already opened ports.
+    /// If the path of the port is known, the controller will try to reopen it
+    /// whenever the connection to the device gets lost.
+    /// If a capture recorder is given, every byte written to or read from the device gets recorded.
     pub fn from_ports(
         writing_port: Box<dyn serialport::SerialPort>,
         reading_port: Box<dyn serialport::SerialPort>,
         tty_port_path: Option<String>,
+        capture: Option<CaptureRecorder>,
     ) -> Self {
-        let listening_exit_flag = Arc::new(AtomicBool::new(false));
-        let listening_exit_flag_clone = Arc::clone(&listening_exit_flag);
+        Self::from_selected_ports(
+            writing_port,
+            reading_port,
+            tty_port_path.clone().map(PortSelector::Path),
+            tty_port_path,
+            capture,
+        )
+    }
 
-        let command_started_flag = Arc::new(AtomicBool::new(false));
-        let command_started_clone = Arc::clone(&command_started_flag);
+    fn from_selected_ports(
+        writing_port: Box<dyn serialport::SerialPort>,
+        reading_port: Box<dyn serialport::SerialPort>,
+        port_selector: Option<PortSelector>,
+        tty_port_path: Option<String>,
+        capture: Option<CaptureRecorder>,
+    ) -> Self {
+        let (response_sender, response_receiver) = mpsc::channel::<u8>();
+        let (port_sender, port_receiver) = mpsc::channel::<Box<dyn SerialPort>>();
+        let shared = SharedState {
+            queue: Arc::new(CommandQueue::new(COMMAND_QUEUE_CAPACITY)),
+            port_sender,
+            response_sender,
+            listening_handle: Arc::new(Mutex::new(None)),
+            listening_exit_flag: Arc::new(AtomicBool::new(false)),
+            command_started_flag: Arc::new(AtomicBool::new(false)),
+            stream_bus: Arc::new(Mutex::new(Bus::new(100))),
+            latest_telemetry: Arc::new(Mutex::new(None)),
+            desired_state: Arc::new(Mutex::new(None)),
+            applied_state: Arc::new(Mutex::new(None)),
+            state_file: Arc::new(Mutex::new(None)),
+            fan_control: Arc::new(Mutex::new(FanControl::default())),
+            failsafe: Arc::new(Mutex::new(Failsafe::default())),
+            config_cache: Arc::new(Mutex::new(ConfigCache::default())),
+            last_update_settings: Arc::new(Mutex::new(None)),
+            connection_status: Arc::new(Mutex::new(ConnectionStatus::default())),
+            traffic: Arc::new(TrafficCounters::default()),
+            identity: Arc::new(Mutex::new(tty_port_path.as_deref().map(BoardIdentity::of_port))),
+            capture: capture.map(Arc::new),
+        };
+        shared.spawn_listener(reading_port);
 
-        let command_delivered_flag = Arc::new(AtomicBool::new(false));
-        let command_delivered_clone = Arc::clone(&command_delivered_flag);
+        let commanding_handle = {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                commanding_thread(writing_port, response_receiver, port_receiver, shared)
+            })
+        };
+        let supervising_handle = port_selector.map(|port_selector| {
+            let shared = shared.clone();
+            std::thread::spawn(move || supervising_thread(port_selector, shared))
+        });
+        let failsafe_handle = {
+            let shared = shared.clone();
+            std::thread::spawn(move || failsafe_thread(shared))
+        };
 
-        let (response_sender, response_receiver) = std::sync::mpsc::sync_channel::<String>(1);
+        Self {
+            shared,
+            handles: Mutex::new(
+                supervising_handle
+                    .into_iter()
+                    .chain([failsafe_handle, commanding_handle])
+                    .collect(),
+            ),
+            simulation: None,
+        }
+    }
 
-        let stream_bus = Arc::new(Mutex::new(Bus::new(100)));
-        let stream_bus_clone = Arc::clone(&stream_bus);
+    /// Queues a command and waits for the complete reply. Without a timeout given,
+    /// the one usual for the command is used. Dropping the future cancels the command,
+    /// unless it's been sent to the device already.
+    pub async fn send_command(
+        &self,
+        command: &Command,
+        priority: Priority,
+        timeout: Option<Duration>,
+    ) -> Result<String, CommandError> {
+        let mut reply_spec = command.reply_spec();
+        if let Some(timeout) = timeout {
+            reply_spec.timeout = timeout;
+        }
+        self.shared.execute(command, priority, reply_spec).await
+    }
 
-        let listening_handle = std::thread::spawn(move || -> Result<(), io::Error> {
-            listening_thread(
-                reading_port,
-                listening_exit_flag_clone,
-                command_started_clone,
-                command_delivered_clone,
-                response_sender,
-                stream_bus_clone,
-            )
-        });
+    /// Sends a command describing the desired state of the board (targets, fan mode, etc.)
+    /// ahead of everything else, and remembers it, so it could be replayed
+    /// if the connection gets lost and restored.
+    pub async fn send_desired_state(&self, command: Command) -> Result<String, CommandError> {
+        let previous = self.shared.desired_state.lock().unwrap().replace(command.clone());
+        self.save_desired_state(previous.as_ref(), &command);
+        if let Command::Update(Update {
+            gpu_temp: Some(..), ..
+        }) = &command
+        {
+            self.shared.failsafe.lock().unwrap().temperatures_arrived();
+        }
+        let command = self.shared.controlled(&command);
+        self.send_command(&command, Priority::High, None).await
+    }
 
-        Self {
-            listening_handle,
-            listening_exit_flag,
-            command_started_flag,
-            command_delivered_flag,
-            tty_port_path,
-            tty_port_and_receiver: Mutex::new((writing_port, response_receiver)),
-            stream_bus,
+    /// Sends fresh GPU temperatures together with the settings of the desired state,
+    /// which then becomes the new desired state. The targets missing from it are set to
+    /// [`DEFAULT_TARGET_TEMP`] and [`DEFAULT_TARGET_MEM`], and the fan mode to automatic,
+    /// so the board never gets the temperatures without the targets to keep them at.
+    pub async fn send_temperatures(
+        &self,
+        core_temp: Vec<i32>,
+        mem_temp: Vec<i32>,
+    ) -> Result<String, CommandError> {
+        let (previous, command) = {
+            let mut desired_state = self.shared.desired_state.lock().unwrap();
+            let update = match desired_state.as_ref() {
+                Some(Command::Update(update)) => update.settings(),
+                _ => Update::default(),
+            };
+            let command = Command::Update(Update {
+                gpu_temp: Some(core_temp).filter(|temps| !temps.is_empty()),
+                gpu_mem: Some(mem_temp).filter(|temps| !temps.is_empty()),
+                target_temp: update.target_temp.or(Some(DEFAULT_TARGET_TEMP)),
+                target_mem: update.target_mem.or(Some(DEFAULT_TARGET_MEM)),
+                fan_mode: update.fan_mode.or(Some(FanMode::Auto)),
+                ..update
+            });
+            (desired_state.replace(command.clone()), command)
+        };
+        self.save_desired_state(previous.as_ref(), &command);
+        self.shared.failsafe.lock().unwrap().temperatures_arrived();
+        let command = self.shared.controlled(&command);
+        self.send_command(&command, Priority::High, None).await
+    }
+
+    pub fn failsafe_status(&self) -> FailsafeStatus {
+        self.shared.failsafe.lock().unwrap().status()
+    }
+
+    /// Enables the fail-safe with the settings, or disables it. The silence is counted afresh.
+    /// If the fail-safe has been engaged, the normal control resumes with the next update.
+    pub fn set_failsafe(&self, settings: Option<FailsafeSettings>) {
+        self.shared.failsafe.lock().unwrap().configure(settings);
+    }
+
+    /// Sends the final command, if any, waiting for the reply, and then stops the threads
+    /// talking to the board. The fail-safe is turned off first, so it can't override
+    /// the final command.
+    pub fn shutdown(&self, final_command: Option<&Command>) -> io::Result<()> {
+        self.set_failsafe(None);
+        if let Some(command) = final_command {
+            match futures::executor::block_on(self.send_command(command, Priority::High, None)) {
+                Ok(reply) => log::info!("Sent the final command, the device replied {:?}", reply),
+                Err(e) => log::error!("Unable to send the final command: {}", e),
+            }
         }
+        self.join()
     }
 
-    pub fn send_command(&self, cmd: &[u8]) -> io::Result<String> {
-        // Just before writing anything, we set a flag "command is executing".
-        // This flag means that all the following bytes coming from the device must be
-        // interpreted as a command's reply, and accumulated.
-        // Once the accumulation is over (after `read` returns a timeout of meets an EOF),
-        // the flag goes down.
-        let mut port_and_receiver = self
-            .tty_port_and_receiver
-            .lock()
-            .expect("The lock must be accessible");
-        self.command_started_flag.store(true, Ordering::Relaxed);
-        port_and_receiver.0.write_all(cmd)?;
-        std::thread::sleep(Duration::from_millis(200));
-        self.command_delivered_flag.store(true, Ordering::Relaxed);
-        log::debug!("Delivered command: {:?}", String::from_utf8_lossy(cmd));
-        match port_and_receiver
-            .1
-            .recv_timeout(Duration::from_millis(1000))
+    pub fn fan_curve(&self) -> FanCurveStatus {
+        self.shared.fan_control.lock().unwrap().curve.clone()
+    }
+
+    /// Sets the fan curve, replacing the PID controller, or removes it to leave the fans
+    /// to the firmware again. The desired state, if there is one, is sent again right away
+    /// to apply the change, and the device's reply is returned.
+    pub async fn set_fan_curve(
+        &self,
+        curve: Option<FanCurve>,
+    ) -> Result<Option<String>, CommandError> {
+        {
+            let mut fan_control = self.shared.fan_control.lock().unwrap();
+            if curve.is_some() {
+                fan_control.pid = None;
+            }
+            fan_control.curve = FanCurveStatus {
+                curve,
+                ..Default::default()
+            };
+        }
+        self.resend_desired_state().await
+    }
+
+    pub fn pid(&self) -> PidStatus {
+        self.shared.fan_control.lock().unwrap().pid_status()
+    }
+
+    /// Makes a PID controller with the settings drive the fans, replacing the fan curve,
+    /// or removes it to leave the fans to the firmware again. The controller starts afresh.
+    /// The desired state, if there is one, is sent again right away, and the device's reply
+    /// is returned.
+    pub async fn set_pid(
+        &self,
+        settings: Option<PidSettings>,
+    ) -> Result<Option<String>, CommandError> {
+        {
+            let mut fan_control = self.shared.fan_control.lock().unwrap();
+            if settings.is_some() {
+                fan_control.curve = FanCurveStatus::default();
+            }
+            fan_control.pid = settings.map(PidController::new);
+        }
+        self.resend_desired_state().await
+    }
+
+    async fn resend_desired_state(&self) -> Result<Option<String>, CommandError> {
+        let desired_state = self.shared.desired_state.lock().unwrap().clone();
+        match desired_state {
+            Some(command) => {
+                let command = self.shared.controlled(&command);
+                Ok(Some(self.send_command(&command, Priority::High, None).await?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the settings of the desired state into the state file, if there is one
+    /// and the settings have changed.
+    fn save_desired_state(&self, previous: Option<&Command>, command: &Command) {
+        let Command::Update(update) = command else {
+            return;
+        };
+        if let Some(Command::Update(previous)) = previous
+            && previous.settings() == update.settings()
+        {
+            return;
+        }
+        if let Some(state_file) = self.shared.state_file.lock().unwrap().as_ref()
+            && let Err(e) = state_file.save(update)
         {
-            Ok(response) => Ok(response),
-            Err(..) => {
-                log::error!("Unable to receive a command's response from the listener");
-                Err(io::Error::new(
-                    io::ErrorKind::BrokenPipe,
-                    "Unable to receive command's response from the listener",
-                ))
+            log::error!(
+                "Unable to save the desired state into {}: {}",
+                state_file.path().display(),
+                e
+            );
+        }
+    }
+
+    /// Persists the desired state of the board in the file from now on, and restores the state
+    /// saved there before: it's sent to the board right away, and after every reconnection.
+    /// An unreadable file is reported, but still gets overwritten by the next desired state.
+    pub fn use_state_file(&self, state_file: StateFile) -> io::Result<()> {
+        let loaded = state_file.load();
+        *self.shared.state_file.lock().unwrap() = Some(state_file);
+        if let Some(state) = loaded? {
+            *self.shared.desired_state.lock().unwrap() = Some(state.update.into());
+            self.shared.replay_desired_state();
+        }
+        Ok(())
+    }
+
+    /// The board's configuration, as reported by `show_config`. It's read once and cached,
+    /// until a command changes the settings, the board gets reconnected, or a refresh is asked for.
+    pub async fn config(&self, refresh: bool) -> Result<CachedConfig, CommandError> {
+        let generation = {
+            let cache = self.shared.config_cache.lock().unwrap();
+            match &cache.cached {
+                Some(cached) if !refresh => return Ok(cached.clone()),
+                _ => cache.generation,
+            }
+        };
+        let reply = self
+            .send_command(&Command::message("show_config"), Priority::Normal, None)
+            .await?;
+        let fetched = CachedConfig {
+            config: BoardConfig::parse(&reply),
+            fetched_at: unix_time_now(),
+        };
+        let mut cache = self.shared.config_cache.lock().unwrap();
+        if cache.generation == generation {
+            cache.cached = Some(fetched.clone());
+        }
+        Ok(fetched)
+    }
+
+    /// Reads the board's settings afresh into a backup document.
+    pub async fn export_config(&self) -> Result<ConfigExport, CommandError> {
+        let cached = self.config(true).await?;
+        Ok(ConfigExport {
+            version: CONFIG_EXPORT_VERSION,
+            exported_at: cached.fetched_at,
+            settings: cached.config.to_settings(),
+        })
+    }
+
+    /// Restores the settings from a backup document, sending them one by one as `key=value`
+    /// messages, and reads the configuration back to see what the board has ended up with.
+    /// The settings taken only in JSON updates, like `target_temp`, are put over the desired
+    /// state, which is then sent. Nothing is sent unless the document passes
+    /// [`ConfigExport::validate`].
+    pub async fn import_config(
+        &self,
+        export: &ConfigExport,
+    ) -> Result<ConfigImportReport, CommandError> {
+        export
+            .validate()
+            .map_err(|e| CommandError::Io(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+        let imported = |key: &String, value: &String, result: &Result<String, CommandError>| {
+            let (reply, error) = match result {
+                Ok(reply) => (Some(reply.clone()), None),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            ImportedSetting {
+                key: key.clone(),
+                requested: value.clone(),
+                actual: None,
+                reply,
+                error,
+            }
+        };
+        let mut settings = Vec::new();
+        for (key, value) in &export.settings {
+            if UPDATE_KEYS.contains(&key.as_str()) {
+                continue;
+            }
+            let command = Command::message(format!("{}={}", key, value));
+            let result = self.send_command(&command, Priority::Normal, None).await;
+            if let Err(CommandError::QueueFull) = result {
+                return Err(CommandError::QueueFull);
             }
+            settings.push(imported(key, value, &result));
         }
+        let desired_update = match self.desired_state() {
+            Some(Command::Update(update)) => update.settings(),
+            _ => Update::default(),
+        };
+        let update = export
+            .update(&desired_update)
+            .map_err(|e| CommandError::Io(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+        if let Some(update) = update {
+            let result = self.send_desired_state(Command::Update(update)).await;
+            if let Err(CommandError::QueueFull) = result {
+                return Err(CommandError::QueueFull);
+            }
+            settings.extend(
+                export
+                    .settings
+                    .iter()
+                    .filter(|(key, _)| UPDATE_KEYS.contains(&key.as_str()))
+                    .map(|(key, value)| imported(key, value, &result)),
+            );
+        }
+        let config = self.config(true).await?;
+        let actual_settings = config.config.to_settings();
+        for setting in &mut settings {
+            setting.actual = actual_settings.get(&setting.key).cloned();
+        }
+        let mismatches = settings
+            .iter()
+            .filter(|setting| !setting.matches())
+            .cloned()
+            .collect::<Vec<_>>();
+        Ok(ConfigImportReport {
+            ok: mismatches.is_empty(),
+            mismatches,
+            settings,
+            config,
+        })
+    }
+
+    pub fn queue_status(&self) -> QueueStatus {
+        self.shared.queue.status()
     }
 
     pub fn subscribe(&self) -> bus::BusReader<Vec<u8>> {
-        self.stream_bus.lock().unwrap().add_rx()
+        self.shared.stream_bus.lock().unwrap().add_rx()
+    }
+
+    /// The most recent telemetry sample, if the board has printed any (only happens in service mode).
+    pub fn latest_telemetry(&self) -> Option<Telemetry> {
+        self.shared.latest_telemetry.lock().ok()?.clone()
+    }
+
+    /// The rig simulated behind an emulated board, see [`CoolboxAutofan::dummy`].
+    pub fn simulation(&self) -> Option<&SharedThermalPlant> {
+        self.simulation.as_ref()
     }
 
     pub fn is_listener_alive(&self) -> bool {
-        !self.listening_handle.is_finished()
+        self.shared
+            .listening_handle
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    pub fn connection_status(&self) -> ConnectionStatus {
+        self.shared.connection_status.lock().unwrap().clone()
+    }
+
+    pub fn traffic_status(&self) -> TrafficStatus {
+        self.shared.traffic.status()
+    }
+
+    /// The last state requested for the board, with the temperatures, if any have been sent.
+    pub fn desired_state(&self) -> Option<Command> {
+        self.shared.desired_state.lock().unwrap().clone()
+    }
+
+    /// The last command sent to the board for the desired state: with the fan speed chosen
+    /// by the fan curve, the PID controller or the fail-safe, if any of them is in charge.
+    pub fn applied_state(&self) -> Option<Command> {
+        self.shared.applied_state.lock().unwrap().clone()
+    }
+
+    /// The path of the tty device the board is currently connected through.
+    pub fn device_path(&self) -> Option<String> {
+        Some(self.identity()?.port)
+    }
+
+    /// The identity of the board's USB-serial adapter, captured when the port was opened.
+    pub fn identity(&self) -> Option<BoardIdentity> {
+        self.shared.identity.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::capture::CaptureRecord;
+    use crate::config::SETTABLE_KEYS;
+    use crate::framing::DIAGNOSTIC_REPLY;
+
+    fn queued(
+        cmd: &str,
+        priority: Priority,
+    ) -> (QueuedCommand, oneshot::Receiver<Result<String, CommandError>>) {
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        let command = QueuedCommand {
+            cmd: cmd.as_bytes().to_vec(),
+            changes_settings: false,
+            update_settings: None,
+            priority,
+            reply_spec: ReplySpec::text(),
+            queued_at: Instant::now(),
+            reply_sender,
+        };
+        (command, reply_receiver)
+    }
+
+    /// Takes all the commands out of the queue, in the order they would be sent.
+    fn drain(queue: &CommandQueue) -> Vec<String> {
+        let mut commands = Vec::new();
+        while queue.status().depth > 0 {
+            let command = queue.pop().unwrap();
+            commands.push(String::from_utf8(command.cmd).unwrap());
+        }
+        commands
+    }
+
+    #[test]
+    fn serves_urgent_commands_first() {
+        let queue = CommandQueue::new(10);
+        let mut receivers = Vec::new();
+        for (cmd, priority) in [
+            ("low", Priority::Low),
+            ("normal 1", Priority::Normal),
+            ("high", Priority::High),
+            ("normal 2", Priority::Normal),
+        ] {
+            let (command, receiver) = queued(cmd, priority);
+            queue.push(command).unwrap();
+            receivers.push(receiver);
+        }
+        assert_eq!(drain(&queue), vec!["high", "normal 1", "normal 2", "low"]);
+        assert_eq!(queue.status().executed, 4);
+    }
+
+    #[test]
+    fn pushes_out_less_urgent_commands_when_full() {
+        let queue = CommandQueue::new(2);
+        let (normal, _normal_receiver) = queued("normal", Priority::Normal);
+        let (low, mut low_receiver) = queued("low", Priority::Low);
+        queue.push(normal).unwrap();
+        queue.push(low).unwrap();
+        let (high, _high_receiver) = queued("high", Priority::High);
+        queue.push(high).unwrap();
+        assert!(matches!(low_receiver.try_recv(), Ok(Some(Err(CommandError::QueueFull)))));
+        // Nothing less urgent left to push out
+        let (normal, _) = queued("another normal", Priority::Normal);
+        assert!(matches!(queue.push(normal), Err(CommandError::QueueFull)));
+        let (low, _) = queued("another low", Priority::Low);
+        assert!(matches!(queue.push(low), Err(CommandError::QueueFull)));
+        assert_eq!(queue.status().rejected, 3);
+        assert_eq!(drain(&queue), vec!["high", "normal"]);
     }
 
-    pub fn device_path(&self) -> Option<&str> {
-        self.tty_port_path.as_deref()
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn drops_abandoned_commands() {
+        let queue = CommandQueue::new(10);
+        let (first, _first_receiver) = queued("first", Priority::Normal);
+        let (abandoned, abandoned_receiver) = queued("abandoned", Priority::Normal);
+        let (last, _last_receiver) = queued("last", Priority::Low);
+        queue.push(first).unwrap();
+        queue.push(abandoned).unwrap();
+        queue.push(last).unwrap();
+        drop(abandoned_receiver);
+        assert_eq!(drain(&queue), vec!["first", "last"]);
+        assert_eq!(queue.status().cancelled, 1);
+    }
+
+    #[test]
+    fn never_writes_abandoned_commands() {
+        let (mut device, port) = TTYPort::pair().unwrap();
+        device.set_timeout(Duration::from_millis(500)).unwrap();
+        let reading_port = port.try_clone().unwrap();
+        let autofan = CoolboxAutofan::from_ports(Box::new(port), reading_port, None, None);
+        let board = &autofan;
+        let send = |text: &'static str| async move {
+            let command = Command::message(text);
+            let timeout = Some(Duration::from_millis(300));
+            board.send_command(&command, Priority::Normal, timeout).await
+        };
+        std::thread::scope(|scope| {
+            // Keeps the commanding thread busy, as the device never replies
+            let busy = scope.spawn(|| futures::executor::block_on(send("busy")));
+            std::thread::sleep(Duration::from_millis(100));
+            // Queued, and abandoned before its turn, like by a disconnected HTTP client
+            assert!(send("abandoned").now_or_never().is_none());
+            assert!(futures::executor::block_on(send("kept")).is_err());
+            assert!(busy.join().unwrap().is_err());
+        });
+        let mut written = Vec::new();
+        let mut buffer = [0; 256];
+        while let Ok(length) = device.read(&mut buffer) {
+            written.extend_from_slice(&buffer[..length]);
+        }
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            r#"{"message":"busy"}{"message":"kept"}"#
+        );
+        let status = autofan.queue_status();
+        assert_eq!((status.executed, status.cancelled), (2, 1));
+        autofan.join().unwrap();
+    }
+
+    #[test]
+    fn keeps_config_cached_across_same_settings() {
+        let autofan = CoolboxAutofan::dummy(SimulationSettings::default(), None).unwrap();
+        let update = |core_temp, target_temp| {
+            Update::for_temperatures(vec![core_temp], vec![], target_temp, 90, None, None).into()
+        };
+        let sent = || autofan.traffic_status().commands_sent;
+        futures::executor::block_on(async {
+            autofan.send_desired_state(update(50, 65)).await.unwrap();
+            autofan.config(false).await.unwrap();
+            assert_eq!(sent(), 2);
+            // Fresh temperatures only, the cached configuration is still good
+            autofan.send_desired_state(update(55, 65)).await.unwrap();
+            autofan.config(false).await.unwrap();
+            assert_eq!(sent(), 3);
+            // A new target, the configuration is read again
+            autofan.send_desired_state(update(55, 70)).await.unwrap();
+            autofan.config(false).await.unwrap();
+            assert_eq!(sent(), 5);
+            // Changed behind the updates' back
+            autofan
+                .send_command(&Command::message("min_pwm_speed=20"), Priority::Normal, None)
+                .await
+                .unwrap();
+            autofan.send_desired_state(update(55, 70)).await.unwrap();
+            let config = autofan.config(false).await.unwrap();
+            assert_eq!(sent(), 8);
+            assert_eq!(config.config.min_pwm_speed, Some(20));
+        });
+        autofan.join().unwrap();
+    }
+
+    #[test]
+    fn sends_temperatures_with_default_targets() {
+        let autofan = CoolboxAutofan::dummy(SimulationSettings::default(), None).unwrap();
+        futures::executor::block_on(async {
+            autofan.send_temperatures(vec![65, 66], vec![]).await.unwrap();
+            let defaults = Update {
+                gpu_temp: Some(vec![65, 66]),
+                target_temp: Some(DEFAULT_TARGET_TEMP),
+                target_mem: Some(DEFAULT_TARGET_MEM),
+                fan_mode: Some(FanMode::Auto),
+                ..Default::default()
+            };
+            assert_eq!(autofan.desired_state(), Some(defaults.into()));
+
+            let manual = Update {
+                target_temp: Some(70),
+                fan_mode: Some(FanMode::Manual),
+                manual_fan_speed: Some(40),
+                ..Default::default()
+            };
+            autofan.send_desired_state(manual.clone().into()).await.unwrap();
+            autofan.send_temperatures(vec![65, 66], vec![80, 82]).await.unwrap();
+            let kept = Update {
+                gpu_temp: Some(vec![65, 66]),
+                gpu_mem: Some(vec![80, 82]),
+                target_mem: Some(DEFAULT_TARGET_MEM),
+                ..manual
+            };
+            assert_eq!(autofan.desired_state(), Some(kept.into()));
+        });
+        autofan.join().unwrap();
+    }
+
+    #[test]
+    fn restores_exported_settings() {
+        let autofan = CoolboxAutofan::dummy(SimulationSettings::default(), None).unwrap();
+        futures::executor::block_on(async {
+            let exported = autofan.export_config().await.unwrap();
+            assert_eq!(exported.settings.len(), SETTABLE_KEYS.len());
+            let mut document = exported.clone();
+            document.settings.insert("min_pwm_speed".to_string(), "20".to_string());
+            document.settings.insert("target_temp".to_string(), "65".to_string());
+            let report = autofan.import_config(&document).await.unwrap();
+            assert!(report.ok, "{:?}", report.mismatches);
+            assert_eq!(report.settings.len(), SETTABLE_KEYS.len() + 1);
+            assert_eq!(report.config.config.min_pwm_speed, Some(20));
+            let setting = |key: &str| report.settings.iter().find(|setting| setting.key == key);
+            let min_pwm_speed = setting("min_pwm_speed").unwrap();
+            assert_eq!(min_pwm_speed.actual.as_deref(), Some("20"));
+            assert_eq!(
+                min_pwm_speed.reply.as_deref(),
+                Some("set min fan speed min_pwm_speed=20 \n\n")
+            );
+            let target_temp = setting("target_temp").unwrap();
+            assert_eq!(target_temp.actual, None);
+            assert_eq!(target_temp.reply.as_deref(), Some(""));
+            assert!(matches!(
+                autofan.desired_state(),
+                Some(Command::Update(Update { target_temp: Some(65), gpu_temp: None, .. }))
+            ));
+            document.settings.remove("target_temp");
+            assert_eq!(autofan.export_config().await.unwrap().settings, document.settings);
+        });
+        autofan.join().unwrap();
+    }
+
+    #[test]
+    fn reports_settings_the_board_has_not_taken() {
+        let autofan = CoolboxAutofan::dummy(SimulationSettings::default(), None).unwrap();
+        let export = |settings: &[(&str, &str)]| ConfigExport {
+            version: CONFIG_EXPORT_VERSION,
+            exported_at: 0,
+            settings: settings
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        };
+        futures::executor::block_on(async {
+            let report = autofan
+                .import_config(&export(&[("min_pwm_speed", "20"), ("pwm_invert", "5")]))
+                .await
+                .unwrap();
+            assert!(!report.ok);
+            assert_eq!(report.settings.len(), 2);
+            assert_eq!(report.config.config.min_pwm_speed, Some(20));
+            assert_eq!(report.config.config.pwm_invert, Some(true));
+            let mismatch = &report.mismatches[..];
+            assert!(matches!(mismatch, [setting] if setting.key == "pwm_invert"));
+            assert_eq!(mismatch[0].actual.as_deref(), Some("1"));
+            assert!(mismatch[0].reply.as_ref().unwrap().starts_with("using inverted pwm"));
+
+            let sent = autofan.traffic_status().commands_sent;
+            let unknown = export(&[("target_temp", "65"), ("osccal", "150")]);
+            assert!(matches!(
+                autofan.import_config(&unknown).await,
+                Err(CommandError::Io(e)) if e.kind() == io::ErrorKind::InvalidInput
+            ));
+            assert_eq!(autofan.traffic_status().commands_sent, sent);
+        });
+        autofan.join().unwrap();
+    }
+
+    #[test]
+    fn tells_partial_replies_from_timeouts() {
+        let (mut device, port) = TTYPort::pair().unwrap();
+        device.set_timeout(Duration::from_secs(2)).unwrap();
+        let reading_port = port.try_clone().unwrap();
+        let autofan = CoolboxAutofan::from_ports(Box::new(port), reading_port, None, None);
+        let timeout = Some(Duration::from_millis(300));
+        let result = futures::executor::block_on(autofan.send_command(
+            &Command::Diagnostic,
+            Priority::Normal,
+            timeout,
+        ));
+        assert!(matches!(result, Err(CommandError::Timeout)), "{:?}", result);
+        assert_eq!(autofan.traffic_status().command_timeouts, 1);
+        let mut diagnostic = [0; 64];
+        let length = device.read(&mut diagnostic).unwrap();
+        assert_eq!(&diagnostic[..length], br#"{"diagnostic":1}"#);
+
+        let device_thread = std::thread::spawn(move || {
+            let mut command = [0; 64];
+            let length = device.read(&mut command).unwrap();
+            // Without the empty line ending the reply
+            device.write_all(b"service mode ON \n").unwrap();
+            (device, String::from_utf8_lossy(&command[..length]).to_string())
+        });
+        let result = futures::executor::block_on(autofan.send_command(
+            &Command::message("service_mode=1"),
+            Priority::Normal,
+            timeout,
+        ));
+        assert!(
+            matches!(&result, Err(CommandError::Partial(reply)) if reply == "service mode ON \n"),
+            "{:?}",
+            result
+        );
+        let (_device, command) = device_thread.join().unwrap();
+        assert_eq!(command, r#"{"message":"service_mode=1"}"#);
+        assert_eq!(autofan.traffic_status().command_timeouts, 2);
+        autofan.join().unwrap();
+    }
+
+    /// A session with firmware 1271, put together from the firmware's format strings,
+    /// as there is no recording of a real board yet: text replies end with an empty line,
+    /// the diagnostic is a line of JSON, updates and fan checks get no reply at all,
+    /// and the telemetry of the service mode comes whenever the board likes.
+    fn firmware_session(update: &Command) -> NamedTempFile {
+        let records = [
+            (0, Direction::Tx, String::from_utf8(update.encode()).unwrap()),
+            (0, Direction::Tx, r#"{"message":"service_mode=1"}"#.to_string()),
+            (40, Direction::Rx, "service mode ON \n".to_string()),
+            (140, Direction::Rx, "\n".to_string()),
+            (0, Direction::Tx, r#"{"diagnostic":1}"#.to_string()),
+            (
+                20,
+                Direction::Rx,
+                "OCR0B=20 OCR0A(max)=200 fan_pwm=10 auto_mode=1 min_t=65 max_t=66 targ_t=70 \
+                 pwm_add=-3 cnt=136 osccal=-2 \n"
+                    .to_string(),
+            ),
+            (60, Direction::Rx, DIAGNOSTIC_REPLY.to_string()),
+            (0, Direction::Tx, r#"{"fan_check":1}"#.to_string()),
+        ];
+        let mut lines = String::new();
+        for (t_ms, dir, data) in records {
+            let record = CaptureRecord {
+                t_ms,
+                dir,
+                data: Some(data),
+                hex: None,
+            };
+            lines += &format!("{}\n", serde_json::to_string(&record).unwrap());
+        }
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), lines).unwrap();
+        file
+    }
+
+    #[test]
+    fn frames_replies_of_the_firmware() {
+        let update: Command = Update::for_temperatures(vec![60], vec![], 70, 90, None, None).into();
+        let session = firmware_session(&update);
+        let autofan = CoolboxAutofan::replay(session.path()).unwrap();
+        let send = |command: &Command| {
+            futures::executor::block_on(autofan.send_command(command, Priority::Normal, None))
+                .unwrap()
+        };
+        assert_eq!(send(&update), "");
+        assert_eq!(send(&Command::message("service_mode=1")), "service mode ON \n\n");
+        assert_eq!(send(&Command::Diagnostic), DIAGNOSTIC_REPLY.trim_end());
+        assert_eq!(send(&Command::FanCheck), "");
+        let traffic = autofan.traffic_status();
+        assert_eq!((traffic.commands_sent, traffic.command_timeouts), (4, 0));
+        autofan.join().unwrap();
+    }
+
+    #[test]
+    fn reconnects_and_replays_desired_state() {
+        // The board can be reopened at the path of the first pair, while the listener
+        // starts on the second one, which is about to go away
+        let (mut device, port) = TTYPort::pair().unwrap();
+        device.set_timeout(Duration::from_secs(5)).unwrap();
+        let (lost_device, reading_port) = TTYPort::pair().unwrap();
+        let path = port.name().unwrap();
+        let autofan =
+            CoolboxAutofan::from_ports(Box::new(port), Box::new(reading_port), Some(path), None);
+        let command = Command::Update(Update { target_temp: Some(65), ..Default::default() });
+        let state = command.encode();
+        futures::executor::block_on(autofan.send_desired_state(command)).unwrap();
+        let mut sent = vec![0; state.len()];
+        device.read_exact(&mut sent).unwrap();
+        assert_eq!(sent, state);
+
+        assert_eq!(autofan.traffic_status().listener_restarts, 0);
+        drop(lost_device);
+        assert!(wait_for(|| autofan.connection_status().reconnects == 1));
+        assert!(wait_for(|| autofan.traffic_status().listener_restarts == 1));
+        assert!(autofan.is_listener_alive());
+        let mut replayed = vec![0; state.len()];
+        device.read_exact(&mut replayed).unwrap();
+        assert_eq!(replayed, state);
+        let status = autofan.connection_status();
+        assert_eq!(status.reconnect_attempts, 1);
+        assert!(status.last_disconnect_error.is_some());
+        autofan.join().unwrap();
     }
 }
@@ -0,0 +1,342 @@
+//! Recording of serial sessions, and replaying them as if they came from a real device.
+//!
+//! A capture is a JSON lines file, one record per chunk of bytes:
+//! ```text
+//! {"t_ms":0,"dir":"tx","data":"{\"diagnostic\":1}"}
+//! {"t_ms":212,"dir":"rx","data":"{\"mcu_version\":\"m328p\",\"pcb_version\":1031,\"fw_version\":1271,\"msg_errors\":0,\"reboot_errors\":0}\n"}
+//! {"t_ms":215,"dir":"rx","hex":"ff00"}
+//! ```
+//! - `t_ms` - milliseconds since the start of the capture;
+//! - `dir` - `tx` for the bytes written to the device, `rx` for the bytes read from it;
+//! - `data` - the bytes as a string, if they are valid UTF-8, otherwise
+//! - `hex` - the bytes in hexadecimal.
+//!
+//! Consecutive bytes going in the same direction are merged into one record until a line ends
+//! or the device pauses.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serialport::{SerialPort, TTYPort};
+
+/// Bytes coming after such a pause start a new record.
+const MAX_CHUNK_GAP_MS: u128 = 20;
+/// How long the replay waits for the service to send a command recorded in the capture.
+const REPLAY_TX_TIMEOUT_MS: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CaptureRecord {
+    pub t_ms: u64,
+    pub dir: Direction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hex: Option<String>,
+}
+
+impl CaptureRecord {
+    fn new(t_ms: u64, dir: Direction, bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Self {
+                t_ms,
+                dir,
+                data: Some(text.to_string()),
+                hex: None,
+            },
+            Err(..) => Self {
+                t_ms,
+                dir,
+                data: None,
+                hex: Some(bytes.iter().map(|b| format!("{:02x}", b)).collect()),
+            },
+        }
+    }
+
+    pub fn bytes(&self) -> io::Result<Vec<u8>> {
+        if let Some(data) = &self.data {
+            return Ok(data.as_bytes().to_vec());
+        }
+        let hex = self.hex.as_deref().unwrap_or_default();
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, format!("Invalid hex {:?}", hex))
+                    })
+            })
+            .collect()
+    }
+}
+
+struct PendingChunk {
+    dir: Direction,
+    started_at: Instant,
+    last_byte_at: Instant,
+    bytes: Vec<u8>,
+}
+
+struct RecorderState {
+    writer: BufWriter<File>,
+    pending: Option<PendingChunk>,
+}
+
+/// Writes everything going through the serial port into a capture file.
+pub struct CaptureRecorder {
+    started_at: Instant,
+    state: Mutex<RecorderState>,
+}
+
+impl CaptureRecorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            started_at: Instant::now(),
+            state: Mutex::new(RecorderState {
+                writer: BufWriter::new(File::create(path)?),
+                pending: None,
+            }),
+        })
+    }
+
+    pub fn record(&self, dir: Direction, bytes: &[u8]) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        let continues_pending = state.pending.as_ref().is_some_and(|pending| {
+            pending.dir == dir
+                && now.duration_since(pending.last_byte_at).as_millis() <= MAX_CHUNK_GAP_MS
+        });
+        if !continues_pending {
+            self.flush_pending(&mut state);
+            state.pending = Some(PendingChunk {
+                dir,
+                started_at: now,
+                last_byte_at: now,
+                bytes: Vec::new(),
+            });
+        }
+        if let Some(pending) = state.pending.as_mut() {
+            pending.bytes.extend_from_slice(bytes);
+            pending.last_byte_at = now;
+        }
+        if bytes.ends_with(b"\n") {
+            self.flush_pending(&mut state);
+        }
+    }
+
+    fn flush_pending(&self, state: &mut RecorderState) {
+        let Some(pending) = state.pending.take() else {
+            return;
+        };
+        let t_ms = pending.started_at.duration_since(self.started_at).as_millis() as u64;
+        let record = CaptureRecord::new(t_ms, pending.dir, &pending.bytes);
+        let line = serde_json::to_string(&record).expect("The record must be serializable");
+        let result = writeln!(state.writer, "{}", line).and_then(|_| state.writer.flush());
+        if let Err(e) = result {
+            log::error!("Unable to write the capture: {}", e);
+        }
+    }
+}
+
+impl Drop for CaptureRecorder {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            self.flush_pending(&mut state);
+        }
+    }
+}
+
+pub fn read_capture(path: &Path) -> io::Result<Vec<CaptureRecord>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for (line_number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str::<CaptureRecord>(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), line_number + 1, e),
+            )
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Plays the role of the device on the device's end of a pseudo-terminal pair,
+/// sending back the recorded replies. Received bytes are sent with the original timing,
+/// counted from the moment the service sent the preceding command.
+fn replay_thread(mut port: TTYPort, records: Vec<CaptureRecord>) -> io::Result<()> {
+    port.set_timeout(Duration::from_millis(100))?;
+    let mut time_base = Instant::now();
+    let mut buffer = [0u8; 256];
+    for record in records {
+        let bytes = record.bytes()?;
+        match record.dir {
+            Direction::Rx => {
+                let due = time_base + Duration::from_millis(record.t_ms);
+                std::thread::sleep(due.saturating_duration_since(Instant::now()));
+                port.write_all(&bytes)?;
+            }
+            Direction::Tx => {
+                let mut received = Vec::<u8>::new();
+                let deadline = Instant::now() + Duration::from_millis(REPLAY_TX_TIMEOUT_MS);
+                while received.len() < bytes.len() && Instant::now() < deadline {
+                    let wanted = (bytes.len() - received.len()).min(buffer.len());
+                    match port.read(&mut buffer[..wanted]) {
+                        Ok(0) => return Ok(()),
+                        Ok(n) => received.extend_from_slice(&buffer[..n]),
+                        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+                        Err(..) => return Ok(()),
+                    }
+                }
+                if received != bytes {
+                    log::warn!(
+                        "Replay expected {:?} to be sent, got {:?}",
+                        String::from_utf8_lossy(&bytes),
+                        String::from_utf8_lossy(&received)
+                    );
+                }
+                // Whatever was recorded after the command is timed relative to it
+                time_base = Instant::now()
+                    .checked_sub(Duration::from_millis(record.t_ms))
+                    .unwrap_or(time_base);
+            }
+        }
+    }
+    log::info!("The replay is over");
+    // Keeping the port open, so the service would not think the device is gone
+    loop {
+        match port.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(..) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(..) => return Ok(()),
+        }
+    }
+}
+
+/// Starts replaying the capture behind the given port.
+pub fn spawn_replay(
+    port: TTYPort,
+    records: Vec<CaptureRecord>,
+) -> std::thread::JoinHandle<io::Result<()>> {
+    std::thread::spawn(move || replay_thread(port, records))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::framing::DIAGNOSTIC_REPLY;
+
+    /// A capture file with the given lines, removed once dropped.
+    fn fixture(lines: &str) -> NamedTempFile {
+        let file = NamedTempFile::new().unwrap();
+        fs::write(file.path(), lines).unwrap();
+        file
+    }
+
+    #[test]
+    fn keeps_text_as_data_and_binary_as_hex() {
+        let text = CaptureRecord::new(212, Direction::Rx, DIAGNOSTIC_REPLY.as_bytes());
+        assert_eq!(text.data.as_deref(), Some(DIAGNOSTIC_REPLY));
+        assert_eq!(text.hex, None);
+        assert_eq!(text.bytes().unwrap(), DIAGNOSTIC_REPLY.as_bytes());
+
+        let binary = CaptureRecord::new(215, Direction::Rx, &[0xff, 0x00, 0x0a]);
+        assert_eq!(binary.data, None);
+        assert_eq!(binary.hex.as_deref(), Some("ff000a"));
+        assert_eq!(binary.bytes().unwrap(), vec![0xff, 0x00, 0x0a]);
+
+        for record in [text, binary] {
+            let line = serde_json::to_string(&record).unwrap();
+            assert_eq!(serde_json::from_str::<CaptureRecord>(&line).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_hex() {
+        for hex in ["ff0", "zz"] {
+            let record = CaptureRecord {
+                t_ms: 0,
+                dir: Direction::Rx,
+                data: None,
+                hex: Some(hex.to_string()),
+            };
+            assert_eq!(record.bytes().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn reads_capture() {
+        let file = fixture(concat!(
+            r#"{"t_ms":0,"dir":"tx","data":"{\"diagnostic\":1}"}"#,
+            "\n\n",
+            r#"{"t_ms":212,"dir":"rx","data":"{\"mcu_version\":\"m328p\",\"pcb_version\":1031,"#,
+            r#"\"fw_version\":1271,\"msg_errors\":0,\"reboot_errors\":0}\n"}"#,
+            "\n",
+            r#"{"t_ms":215,"dir":"rx","hex":"ff00"}"#,
+            "\n",
+        ));
+        let records = read_capture(file.path()).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].dir, Direction::Tx);
+        assert_eq!(records[0].bytes().unwrap(), b"{\"diagnostic\":1}");
+        assert_eq!(records[1].t_ms, 212);
+        assert_eq!(records[1].bytes().unwrap(), DIAGNOSTIC_REPLY.as_bytes());
+        assert_eq!(records[2].bytes().unwrap(), vec![0xff, 0x00]);
+    }
+
+    #[test]
+    fn tells_malformed_line() {
+        let file = fixture(
+            "{\"t_ms\":0,\"dir\":\"tx\",\"data\":\"{}\"}\n\
+             {\"t_ms\":212,\"dir\":\"sideways\",\"data\":\"\"}\n",
+        );
+        let error = read_capture(file.path()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let location = format!("{}:2:", file.path().display());
+        assert!(error.to_string().starts_with(&location), "{}", error);
+    }
+
+    #[test]
+    fn records_what_it_reads_back() {
+        let file = fixture("");
+        let recorder = CaptureRecorder::create(file.path()).unwrap();
+        recorder.record(Direction::Tx, b"{\"diagnostic\":1}");
+        recorder.record(Direction::Rx, DIAGNOSTIC_REPLY.as_bytes());
+        recorder.record(Direction::Rx, &[0xff]);
+        drop(recorder);
+        let records = read_capture(file.path()).unwrap();
+        let chunks = records
+            .iter()
+            .map(|record| (record.dir, record.bytes().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chunks,
+            vec![
+                (Direction::Tx, b"{\"diagnostic\":1}".to_vec()),
+                (Direction::Rx, DIAGNOSTIC_REPLY.as_bytes().to_vec()),
+                (Direction::Rx, vec![0xff]),
+            ]
+        );
+    }
+}
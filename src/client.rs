@@ -0,0 +1,450 @@
+//! Client commands, talking to a running service or straight to the board.
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use argh::FromArgs;
+use serde_json::{Value, json};
+
+use coolbox_rs::autofan::{CoolboxAutofan, Priority};
+use coolbox_rs::command::{Command, Update};
+use coolbox_rs::config::parse_settings_line;
+use coolbox_rs::telemetry::TelemetryParser;
+
+/// Comma-separated temperatures, like "65,66".
+#[derive(Debug, Clone, PartialEq)]
+pub struct TempList(Vec<i32>);
+
+impl std::str::FromStr for TempList {
+    type Err = String;
+
+    fn from_str(temps: &str) -> Result<Self, Self::Err> {
+        temps
+            .split(',')
+            .map(str::trim)
+            .filter(|temp| !temp.is_empty())
+            .map(|temp| {
+                temp.parse::<i32>()
+                    .map_err(|_| format!("Invalid temperature {:?}", temp))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(TempList)
+    }
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+pub enum ClientCommand {
+    Status(StatusCommand),
+    Update(UpdateCommand),
+    FanCheck(FanCheckCommand),
+    Diagnostic(DiagnosticCommand),
+    Message(MessageCommand),
+    Watch(WatchCommand),
+}
+
+/// show the health of the board and its latest telemetry
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "status")]
+pub struct StatusCommand {}
+
+/// send GPU temperatures and targets to the board
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "update")]
+pub struct UpdateCommand {
+    /// GPU core temperatures, comma-separated, like "65,66"
+    #[argh(option)]
+    core: Option<TempList>,
+
+    /// GPU VRAM temperatures, comma-separated, like "80,76"
+    #[argh(option)]
+    mem: Option<TempList>,
+
+    /// target GPU core temperature
+    #[argh(option)]
+    target_core: i32,
+
+    /// target GPU VRAM temperature
+    #[argh(option)]
+    target_mem: i32,
+
+    /// watchdog reset interval, in minutes. If not given, the watchdog is turned off
+    #[argh(option)]
+    watchdog: Option<u32>,
+
+    /// manual fan speed, in percent. If not given, the speed is chosen automatically
+    #[argh(option)]
+    fan_speed: Option<u8>,
+}
+
+/// spin the fans through their whole range
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "fan-check")]
+pub struct FanCheckCommand {}
+
+/// show the firmware version and the current state of the board
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "diagnostic")]
+pub struct DiagnosticCommand {}
+
+/// send a text message, like "service_mode=1" or "show_config"
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "message")]
+pub struct MessageCommand {
+    /// the message
+    #[argh(positional)]
+    text: String,
+}
+
+/// print the device's output as it comes
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "watch")]
+pub struct WatchCommand {
+    /// turn the service mode on first, so the board would print its telemetry
+    #[argh(switch)]
+    service_mode: bool,
+}
+
+/// Where the client commands go.
+pub enum Backend {
+    /// A running service, addressed by the base URL of the board's API,
+    /// like "http://127.0.0.1:65231/api".
+    Service { api_url: String },
+    /// The board itself.
+    Board(CoolboxAutofan),
+}
+
+/// How to print the results.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+pub fn run(command: ClientCommand, backend: Backend, format: OutputFormat) -> io::Result<()> {
+    match command {
+        ClientCommand::Status(..) => print_value(&mut io::stdout(), &status(&backend)?, format),
+        ClientCommand::Update(update) => {
+            let core_temp = update.core.map(|temps| temps.0).unwrap_or_default();
+            let mem_temp = update.mem.map(|temps| temps.0).unwrap_or_default();
+            if core_temp.len() != mem_temp.len() && !core_temp.is_empty() && !mem_temp.is_empty() {
+                return Err(io::Error::other(
+                    "Both lists of core and VRAM temps, if provided, must be of the same size",
+                ));
+            }
+            let reply = match &backend {
+                Backend::Service { api_url } => service_command(
+                    ureq::post(&format!("{}/update", api_url)),
+                    Some(json!({
+                        "core_temp": core_temp,
+                        "mem_temp": mem_temp,
+                        "target_core_temp": update.target_core,
+                        "target_mem_temp": update.target_mem,
+                        "watchdog_interval": update.watchdog,
+                        "fan_speed": update.fan_speed,
+                    })),
+                )?,
+                Backend::Board(autofan) => {
+                    let command = Update::for_temperatures(
+                        core_temp,
+                        mem_temp,
+                        update.target_core,
+                        update.target_mem,
+                        update.watchdog,
+                        update.fan_speed,
+                    );
+                    futures::executor::block_on(autofan.send_desired_state(command.into()))?
+                }
+            };
+            print_reply(&mut io::stdout(), &reply, format)
+        }
+        ClientCommand::FanCheck(..) => {
+            let reply = device_command(&backend, &Command::FanCheck, "fan-check", None)?;
+            print_reply(&mut io::stdout(), &reply, format)
+        }
+        ClientCommand::Diagnostic(..) => {
+            let reply = device_command(&backend, &Command::Diagnostic, "diagnostic", None)?;
+            print_reply(&mut io::stdout(), &reply, format)
+        }
+        ClientCommand::Message(message) => {
+            let reply = device_command(
+                &backend,
+                &Command::message(message.text.clone()),
+                "message",
+                Some(json!({"text": message.text})),
+            )?;
+            print_reply(&mut io::stdout(), &reply, format)
+        }
+        ClientCommand::Watch(watch_command) => watch(&backend, watch_command, format),
+    }
+}
+
+/// Sends a request to the service, returning the HTTP status and the JSON it has replied with.
+fn call_service(request: ureq::Request, body: Option<Value>) -> io::Result<(u16, Value)> {
+    let result = match body {
+        Some(body) => request.send_json(body),
+        None => request.call(),
+    };
+    let response = match result {
+        Ok(response) => response,
+        Err(ureq::Error::Status(.., response)) => response,
+        Err(e) => {
+            return Err(io::Error::other(format!("Unable to reach the service: {}", e)));
+        }
+    };
+    let status = response.status();
+    let reply = response.into_json::<Value>().unwrap_or_default();
+    Ok((status, reply))
+}
+
+/// Sends a command through the service, returning the device's reply.
+fn service_command(request: ureq::Request, body: Option<Value>) -> io::Result<String> {
+    let (status, reply) = call_service(request, body)?;
+    match (&reply["device_reply"], &reply["error"]) {
+        (Value::String(device_reply), ..) => Ok(device_reply.clone()),
+        (.., Value::String(error)) => Err(io::Error::other(error.clone())),
+        _ => Err(io::Error::other(format!("The service has replied with {}", status))),
+    }
+}
+
+fn device_command(
+    backend: &Backend,
+    command: &Command,
+    endpoint: &str,
+    body: Option<Value>,
+) -> io::Result<String> {
+    match backend {
+        Backend::Service { api_url } => {
+            service_command(ureq::post(&format!("{}/{}", api_url, endpoint)), body)
+        }
+        Backend::Board(autofan) => Ok(futures::executor::block_on(autofan.send_command(
+            command,
+            Priority::Normal,
+            None,
+        ))?),
+    }
+}
+
+fn status(backend: &Backend) -> io::Result<Value> {
+    match backend {
+        Backend::Service { api_url } => {
+            let (.., mut health) = call_service(ureq::get(&format!("{}/health", api_url)), None)?;
+            if !health.is_object() {
+                return Err(io::Error::other("The service has replied with no health"));
+            }
+            let (status, telemetry) =
+                call_service(ureq::get(&format!("{}/telemetry", api_url)), None)?;
+            health["telemetry"] = if status == 200 { telemetry } else { Value::Null };
+            Ok(health)
+        }
+        Backend::Board(autofan) => {
+            let reply = futures::executor::block_on(autofan.send_command(
+                &Command::Diagnostic,
+                Priority::Normal,
+                None,
+            ))?;
+            let diagnostic = serde_json::from_str::<Value>(reply.trim()).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("The board has replied with a garbled diagnostic {:?}: {}", reply, e),
+                )
+            })?;
+            Ok(json!({
+                "device": autofan.device_path(),
+                "identity": autofan.identity(),
+                "diagnostic": diagnostic,
+            }))
+        }
+    }
+}
+
+fn watch(backend: &Backend, watch_command: WatchCommand, format: OutputFormat) -> io::Result<()> {
+    if watch_command.service_mode {
+        device_command(
+            backend,
+            &Command::message("service_mode=1"),
+            "message",
+            Some(json!({"text": "service_mode=1"})),
+        )?;
+    }
+    let mut telemetry_parser = TelemetryParser::default();
+    let mut pending_line = Vec::<u8>::new();
+    let mut print_output = |output: &[u8]| -> io::Result<()> {
+        match format {
+            OutputFormat::Table => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(output)?;
+                stdout.flush()
+            }
+            // Only the telemetry samples, one JSON object per line
+            OutputFormat::Json => {
+                for &byte in output {
+                    pending_line.push(byte);
+                    if byte != b'\n' {
+                        continue;
+                    }
+                    let line = String::from_utf8_lossy(&std::mem::take(&mut pending_line))
+                        .to_string();
+                    if let Some(telemetry) = telemetry_parser.feed_line(&line) {
+                        println!("{}", serde_json::to_string(&telemetry)?);
+                    }
+                }
+                Ok(())
+            }
+        }
+    };
+    match backend {
+        Backend::Service { api_url } => {
+            let response = ureq::get(&format!("{}/watch", api_url))
+                .call()
+                .map_err(|e| io::Error::other(format!("Unable to watch the device: {}", e)))?;
+            let mut reader = response.into_reader();
+            let mut buffer = [0u8; 1024];
+            loop {
+                match reader.read(&mut buffer)? {
+                    0 => return Ok(()),
+                    bytes => print_output(&buffer[..bytes])?,
+                }
+            }
+        }
+        Backend::Board(autofan) => {
+            let mut receiver = autofan.subscribe();
+            loop {
+                match receiver.recv_timeout(Duration::from_secs(1)) {
+                    Ok(output) => print_output(&output)?,
+                    Err(..) if !autofan.is_listener_alive() => {
+                        return Err(io::Error::other("Lost the device"));
+                    }
+                    Err(..) => {}
+                }
+            }
+        }
+    }
+}
+
+fn print_reply(out: &mut impl Write, reply: &str, format: OutputFormat) -> io::Result<()> {
+    match format {
+        OutputFormat::Json => print_value(out, &json!({"device_reply": reply}), format),
+        OutputFormat::Table => {
+            if let Ok(value @ Value::Object(..)) = serde_json::from_str(reply.trim()) {
+                return print_value(out, &value, format);
+            }
+            // Lines of settings, like "PID pid_kp=10 pid_kd=5", make a table,
+            // the rest is printed as is
+            let mut rows = Vec::new();
+            for line in reply.lines().filter(|line| !line.trim().is_empty()) {
+                match parse_settings_line(line) {
+                    Some(settings) if !settings.is_empty() => rows.extend(
+                        settings.into_iter().map(|(key, value)| (key, value.to_string())),
+                    ),
+                    _ => {
+                        print_table(out, &std::mem::take(&mut rows))?;
+                        writeln!(out, "{}", line.trim_end())?;
+                    }
+                }
+            }
+            print_table(out, &rows)
+        }
+    }
+}
+
+fn print_value(out: &mut impl Write, value: &Value, format: OutputFormat) -> io::Result<()> {
+    match format {
+        OutputFormat::Json => writeln!(out, "{}", serde_json::to_string_pretty(value)?),
+        OutputFormat::Table => {
+            let mut rows = Vec::new();
+            flatten(String::new(), value, &mut rows);
+            print_table(out, &rows)
+        }
+    }
+}
+
+/// Turns nested objects into rows like ("connection.reconnects", "0").
+fn flatten(prefix: String, value: &Value, rows: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(key, value, rows);
+            }
+        }
+        Value::Null => rows.push((prefix, "-".into())),
+        Value::String(text) => rows.push((prefix, text.clone())),
+        other => rows.push((prefix, other.to_string())),
+    }
+}
+
+fn print_table(out: &mut impl Write, rows: &[(String, String)]) -> io::Result<()> {
+    let width = rows.iter().map(|(key, ..)| key.len()).max().unwrap_or(0);
+    for (key, value) in rows {
+        writeln!(out, "{:width$}  {}", key, value, width = width)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_temperature_lists() {
+        assert_eq!("65,66".parse(), Ok(TempList(vec![65, 66])));
+        assert_eq!(" 65 , -5 ,".parse(), Ok(TempList(vec![65, -5])));
+        assert_eq!("".parse(), Ok(TempList(vec![])));
+        assert_eq!(
+            "65,hot".parse::<TempList>(),
+            Err("Invalid temperature \"hot\"".to_string())
+        );
+    }
+
+    #[test]
+    fn flattens_nested_objects() {
+        let mut rows = Vec::new();
+        let status = json!({
+            "connection": {"alive": true, "reconnects": 0},
+            "device": "/dev/ttyUSB0",
+            "telemetry": null,
+        });
+        flatten(String::new(), &status, &mut rows);
+        let rows = rows.iter().map(|(key, value)| (key.as_str(), value.as_str()));
+        assert!(rows.eq([
+            ("connection.alive", "true"),
+            ("connection.reconnects", "0"),
+            ("device", "/dev/ttyUSB0"),
+            ("telemetry", "-"),
+        ]));
+    }
+
+    fn printed(reply: &str, format: OutputFormat) -> String {
+        let mut out = Vec::new();
+        print_reply(&mut out, reply, format).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn prints_replies() {
+        let show_config =
+            "fw_version=1171 \nPID pid_kp=10 pid_kd=5 \nOSCCAL diff=-2 OSCCAL=146 \n\n";
+        assert_eq!(
+            printed(show_config, OutputFormat::Table),
+            "fw_version   1171\npid_kp       10\npid_kd       5\n\
+             osccal_diff  -2\nosccal       146\n"
+        );
+        assert_eq!(
+            printed("service mode ON \n\nmin_mem_t=40 \n", OutputFormat::Table),
+            "service mode ON\nmin_mem_t  40\n"
+        );
+        assert_eq!(
+            printed(r#"{"mcu_version":"m328p","pcb_version":1031}"#, OutputFormat::Table),
+            "mcu_version  m328p\npcb_version  1031\n"
+        );
+        assert_eq!(
+            printed("service mode ON \n\n", OutputFormat::Json),
+            "{\n  \"device_reply\": \"service mode ON \\n\\n\"\n}\n"
+        );
+    }
+}
@@ -0,0 +1,317 @@
+//! Messages understood by the Coolbox Autofan firmware.
+//!
+//! Every command is a single JSON object written to the board's serial port, for instance
+//! ```text
+//! {"gpu_temp":[65,66],"gpu_mem":[80,76],"target_temp":70,"target_mem":90,"fan_mode":2}
+//! {"fan_check":1}
+//! {"message":"service_mode=1"}
+//! ```
+
+use serde::ser::SerializeMap;
+
+use crate::framing::ReplySpec;
+
+/// How the board drives the fans.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(into = "i32", try_from = "i32")]
+pub enum FanMode {
+    /// The fans spin at `manual_fan_speed`.
+    Manual,
+    /// The board adjusts the fan speed to keep the GPUs at their target temperatures.
+    Auto,
+}
+
+impl From<FanMode> for i32 {
+    fn from(mode: FanMode) -> Self {
+        match mode {
+            FanMode::Manual => 1,
+            FanMode::Auto => 2,
+        }
+    }
+}
+
+impl TryFrom<i32> for FanMode {
+    type Error = String;
+
+    fn try_from(mode: i32) -> Result<Self, Self::Error> {
+        match mode {
+            1 => Ok(Self::Manual),
+            2 => Ok(Self::Auto),
+            _ => Err(format!("Unknown fan mode {}", mode)),
+        }
+    }
+}
+
+/// The firmware takes flags as 0 and 1.
+fn serialize_flag<S: serde::Serializer>(
+    flag: &Option<bool>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match flag {
+        Some(flag) => serializer.serialize_some(&(*flag as i32)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_flag<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<bool>, D::Error> {
+    let flag = <Option<i32> as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(flag.map(|flag| flag != 0))
+}
+
+/// Temperatures and settings sent to the board in one message. Only the given fields are sent,
+/// the board keeps the previous values of the rest.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Update {
+    /// GPU core temperatures, one per GPU.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpu_temp: Option<Vec<i32>>,
+    /// GPU VRAM temperatures, one per GPU.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpu_mem: Option<Vec<i32>>,
+    /// Target GPU core temperature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_temp: Option<i32>,
+    /// Target GPU VRAM temperature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_mem: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fan_mode: Option<FanMode>,
+    /// Fan speed in the manual mode, in percent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manual_fan_speed: Option<u8>,
+    /// Whether the board should reset the rig when the updates stop coming.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_flag",
+        deserialize_with = "deserialize_flag"
+    )]
+    pub watchdog: Option<bool>,
+    /// How long the watchdog waits for an update, in minutes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wd_reset_interval: Option<u32>,
+}
+
+impl Update {
+    /// The regular update of the service: the temperatures (an empty list is not sent), the targets,
+    /// and automatic fan control, unless a manual fan speed is given.
+    /// The watchdog gets turned on if its interval is given and isn't 0.
+    pub fn for_temperatures(
+        core_temp: Vec<i32>,
+        mem_temp: Vec<i32>,
+        target_core_temp: i32,
+        target_mem_temp: i32,
+        watchdog_interval: Option<u32>,
+        fan_speed: Option<u8>,
+    ) -> Self {
+        Self {
+            gpu_temp: Some(core_temp).filter(|temps| !temps.is_empty()),
+            gpu_mem: Some(mem_temp).filter(|temps| !temps.is_empty()),
+            target_temp: Some(target_core_temp),
+            target_mem: Some(target_mem_temp),
+            fan_mode: Some(match fan_speed {
+                Some(..) => FanMode::Manual,
+                None => FanMode::Auto,
+            }),
+            manual_fan_speed: Some(fan_speed.unwrap_or(0)),
+            watchdog: Some(watchdog_interval.is_some_and(|interval| interval != 0)),
+            wd_reset_interval: watchdog_interval,
+        }
+    }
+
+    /// The same update without the temperatures.
+    pub fn settings(&self) -> Self {
+        Self {
+            gpu_temp: None,
+            gpu_mem: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// A command of the firmware.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Updates temperatures and settings.
+    Update(Update),
+    /// Spins the fans through their whole range.
+    FanCheck,
+    /// Reports the firmware and PCB versions, and the current state.
+    Diagnostic,
+    /// A text message, like `service_mode=1`, `show_config`, `default` or `<setting>=<value>`.
+    Message(String),
+}
+
+impl serde::Serialize for Command {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Update(update) => update.serialize(serializer),
+            Self::FanCheck => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("fan_check", &1)?;
+                map.end()
+            }
+            Self::Diagnostic => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("diagnostic", &1)?;
+                map.end()
+            }
+            Self::Message(text) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("message", text)?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl Command {
+    pub fn message(text: impl Into<String>) -> Self {
+        Self::Message(text.into())
+    }
+
+    /// The bytes to write to the board's serial port.
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("The command must be serializable")
+    }
+
+    /// Whether the command may change the settings reported by `show_config`.
+    pub fn changes_settings(&self) -> bool {
+        match self {
+            Self::Update(update) => {
+                update.target_temp.is_some()
+                    || update.target_mem.is_some()
+                    || update.fan_mode.is_some()
+                    || update.manual_fan_speed.is_some()
+                    || update.watchdog.is_some()
+                    || update.wd_reset_interval.is_some()
+            }
+            Self::Message(text) => {
+                let text = text.trim();
+                text == "default" || (text.contains('=') && !text.starts_with("service_mode"))
+            }
+            Self::FanCheck | Self::Diagnostic => false,
+        }
+    }
+
+    /// What the reply to the command looks like and how long to wait for it.
+    pub fn reply_spec(&self) -> ReplySpec {
+        match self {
+            // The firmware spins the fans or takes the update without a word
+            Self::Update(..) | Self::FanCheck => ReplySpec::unacknowledged(),
+            Self::Diagnostic => ReplySpec::json(),
+            Self::Message(..) => ReplySpec::text(),
+        }
+    }
+}
+
+impl From<Update> for Command {
+    fn from(update: Update) -> Self {
+        Self::Update(update)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(command: Command) -> String {
+        String::from_utf8(command.encode()).unwrap()
+    }
+
+    #[test]
+    fn encodes_simple_commands() {
+        assert_eq!(encoded(Command::FanCheck), r#"{"fan_check":1}"#);
+        assert_eq!(encoded(Command::Diagnostic), r#"{"diagnostic":1}"#);
+        assert_eq!(
+            encoded(Command::message("service_mode=1")),
+            r#"{"message":"service_mode=1"}"#
+        );
+    }
+
+    #[test]
+    fn escapes_messages() {
+        assert_eq!(
+            encoded(Command::message("say \"hi\"\n")),
+            r#"{"message":"say \"hi\"\n"}"#
+        );
+    }
+
+    #[test]
+    fn encodes_full_update_in_firmware_order() {
+        let update = Update {
+            gpu_temp: Some(vec![65, 66]),
+            gpu_mem: Some(vec![80, 76]),
+            target_temp: Some(70),
+            target_mem: Some(90),
+            fan_mode: Some(FanMode::Auto),
+            manual_fan_speed: Some(0),
+            watchdog: Some(true),
+            wd_reset_interval: Some(5),
+        };
+        assert_eq!(
+            encoded(update.into()),
+            r#"{"gpu_temp":[65,66],"gpu_mem":[80,76],"target_temp":70,"target_mem":90,"fan_mode":2,"manual_fan_speed":0,"watchdog":1,"wd_reset_interval":5}"#
+        );
+    }
+
+    #[test]
+    fn skips_missing_update_fields() {
+        let update = Update {
+            fan_mode: Some(FanMode::Manual),
+            manual_fan_speed: Some(70),
+            watchdog: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            encoded(update.into()),
+            r#"{"fan_mode":1,"manual_fan_speed":70,"watchdog":0}"#
+        );
+        assert_eq!(encoded(Update::default().into()), "{}");
+    }
+
+    #[test]
+    fn decodes_updates() {
+        let update =
+            serde_json::from_str::<Update>(r#"{"gpu_temp":[60],"fan_mode":2,"watchdog":1}"#)
+                .unwrap();
+        assert_eq!(
+            update,
+            Update {
+                gpu_temp: Some(vec![60]),
+                fan_mode: Some(FanMode::Auto),
+                watchdog: Some(true),
+                ..Default::default()
+            }
+        );
+        assert!(serde_json::from_str::<Update>(r#"{"fan_mode":3}"#).is_err());
+    }
+
+    #[test]
+    fn expects_replies_the_firmware_gives() {
+        let update = Command::from(Update::for_temperatures(vec![60], vec![], 70, 90, None, None));
+        assert_eq!(update.reply_spec(), ReplySpec::unacknowledged());
+        assert_eq!(Command::FanCheck.reply_spec(), ReplySpec::unacknowledged());
+        assert_eq!(Command::Diagnostic.reply_spec(), ReplySpec::json());
+        assert_eq!(Command::message("show_config").reply_spec(), ReplySpec::text());
+    }
+
+    #[test]
+    fn tells_setting_changes() {
+        assert!(Command::from(Update::for_temperatures(vec![60], vec![], 70, 90, None, None))
+            .changes_settings());
+        let temperatures_only = Update {
+            gpu_temp: Some(vec![60]),
+            ..Default::default()
+        };
+        assert!(!Command::from(temperatures_only).changes_settings());
+        assert!(Command::message("min_pwm_speed=20").changes_settings());
+        assert!(Command::message("default").changes_settings());
+        assert!(!Command::message("service_mode=1").changes_settings());
+        assert!(!Command::message("show_config").changes_settings());
+        assert!(!Command::FanCheck.changes_settings());
+        assert!(!Command::Diagnostic.changes_settings());
+    }
+}
@@ -0,0 +1,477 @@
+use std::collections::BTreeMap;
+
+use crate::command::Update;
+
+/// The settings of the board, as reported by `show_config`, like
+/// ```text
+/// fw_version=1171
+/// PID pid_kp=10 pid_kd=5 pid_ki=1 pid_tstep=2
+/// fan_check_time=5
+/// pwm_frequency=25
+/// ...
+/// OSCCAL diff=-2 OSCCAL=146
+/// fan_number=6
+/// ```
+/// Every field is optional, because different firmware versions report different settings.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct BoardConfig {
+    /// The firmware version, as `show_config` prints it.
+    #[cfg_attr(feature = "openapi", schema(example = 1171))]
+    pub fw_version: Option<i32>,
+    /// Proportional coefficient of the firmware's own fan controller.
+    pub pid_kp: Option<i32>,
+    /// Derivative coefficient of the firmware's own fan controller.
+    pub pid_kd: Option<i32>,
+    /// Integral coefficient of the firmware's own fan controller.
+    pub pid_ki: Option<i32>,
+    /// Time step of the firmware's own fan controller.
+    pub pid_tstep: Option<i32>,
+    /// How long the fans are checked by `fan_check`.
+    pub fan_check_time: Option<i32>,
+    /// PWM frequency of the fans, in kHz. Set with the `pwm_freq` message.
+    #[cfg_attr(feature = "openapi", schema(example = 25))]
+    pub pwm_frequency: Option<i32>,
+    /// Whether the PWM is inverted, for the fans spinning at full speed at 0%.
+    pub pwm_invert: Option<bool>,
+    /// The slowest the board lets the fans spin, in percent.
+    #[cfg_attr(feature = "openapi", schema(example = 10))]
+    pub min_pwm_speed: Option<i32>,
+    /// The fastest the board lets the fans spin in the automatic mode, in percent.
+    #[cfg_attr(feature = "openapi", schema(example = 100))]
+    pub max_autofan_pwm: Option<i32>,
+    /// Fan speed after the rig boots, in percent.
+    #[cfg_attr(feature = "openapi", schema(example = 70))]
+    pub start_pwm_speed: Option<i32>,
+    /// How long the watchdog pushes the power button.
+    pub wdt_push_time: Option<i32>,
+    /// How long the watchdog waits after shutting the rig down before waking it up.
+    pub wdt_wait_time: Option<i32>,
+    /// Correction of the first thermosensor.
+    pub thermo_offset1: Option<i32>,
+    /// Correction of the second thermosensor.
+    pub thermo_offset2: Option<i32>,
+    /// Whether the LED blinks.
+    pub led_blink: Option<bool>,
+    /// Calibration of the board's oscillator: the difference from the factory value.
+    pub osccal_diff: Option<i32>,
+    /// Calibration of the board's oscillator.
+    pub osccal: Option<i32>,
+    /// The number of fans.
+    #[cfg_attr(feature = "openapi", schema(example = 6))]
+    pub fan_number: Option<i32>,
+    /// Everything else the firmware has reported, as is.
+    pub extra: BTreeMap<String, String>,
+}
+
+impl BoardConfig {
+    /// Parses the reply to `show_config`, line by line with [`parse_settings_line`].
+    /// Unknown settings, and the known ones with unexpected values, end up in `extra`.
+    /// Lines without settings are skipped.
+    pub fn parse(reply: &str) -> Self {
+        let mut config = Self::default();
+        for (key, value) in reply.lines().filter_map(parse_settings_line).flatten() {
+            config.set(key, value);
+        }
+        config
+    }
+
+    fn set(&mut self, key: String, value: &str) {
+        let number = value.parse::<i32>().ok();
+        match (key.as_str(), number) {
+            ("fw_version", Some(number)) => self.fw_version = Some(number),
+            ("pid_kp", Some(number)) => self.pid_kp = Some(number),
+            ("pid_kd", Some(number)) => self.pid_kd = Some(number),
+            ("pid_ki", Some(number)) => self.pid_ki = Some(number),
+            ("pid_tstep", Some(number)) => self.pid_tstep = Some(number),
+            ("fan_check_time", Some(number)) => self.fan_check_time = Some(number),
+            ("pwm_frequency", Some(number)) => self.pwm_frequency = Some(number),
+            ("pwm_invert", Some(number)) => self.pwm_invert = Some(number != 0),
+            ("min_pwm_speed", Some(number)) => self.min_pwm_speed = Some(number),
+            ("max_autofan_pwm", Some(number)) => self.max_autofan_pwm = Some(number),
+            ("start_pwm_speed", Some(number)) => self.start_pwm_speed = Some(number),
+            ("wdt_push_time", Some(number)) => self.wdt_push_time = Some(number),
+            ("wdt_wait_time", Some(number)) => self.wdt_wait_time = Some(number),
+            ("thermo_offset1", Some(number)) => self.thermo_offset1 = Some(number),
+            ("thermo_offset2", Some(number)) => self.thermo_offset2 = Some(number),
+            ("led_blink", Some(number)) => self.led_blink = Some(number != 0),
+            ("osccal_diff", Some(number)) => self.osccal_diff = Some(number),
+            ("osccal", Some(number)) => self.osccal = Some(number),
+            ("fan_number", Some(number)) => self.fan_number = Some(number),
+            _ => {
+                self.extra.insert(key, value.to_string());
+            }
+        }
+    }
+
+    /// The settable settings as the firmware's messages spell them, ready to be sent back
+    /// as `key=value` messages. The settings in `extra` are left out.
+    pub fn to_settings(&self) -> BTreeMap<String, String> {
+        let settable = [
+            ("pid_kp", self.pid_kp),
+            ("pid_kd", self.pid_kd),
+            ("pid_ki", self.pid_ki),
+            ("pid_tstep", self.pid_tstep),
+            ("fan_check_time", self.fan_check_time),
+            ("pwm_freq", self.pwm_frequency),
+            ("pwm_invert", self.pwm_invert.map(i32::from)),
+            ("min_pwm_speed", self.min_pwm_speed),
+            ("max_autofan_pwm", self.max_autofan_pwm),
+            ("start_pwm_speed", self.start_pwm_speed),
+            ("wdt_push_time", self.wdt_push_time),
+            ("wdt_wait_time", self.wdt_wait_time),
+            ("thermo_offset1", self.thermo_offset1),
+            ("thermo_offset2", self.thermo_offset2),
+            ("led_blink", self.led_blink.map(i32::from)),
+            ("fan_number", self.fan_number),
+        ];
+        settable
+            .into_iter()
+            .filter_map(|(key, value)| Some((key.to_string(), value?.to_string())))
+            .collect()
+    }
+}
+
+/// Splits a line printed by the firmware into settings: `key=value` pairs, which may follow
+/// a label, like `PID pid_kp=10 pid_kd=5` or `OSCCAL diff=-2 OSCCAL=146`. The keys are
+/// lowercased, and the label is put in front of those not starting with it already, so the
+/// latter line gives `osccal_diff` and `osccal`. Returns `None` if the line has other words,
+/// like a sentence.
+pub fn parse_settings_line(line: &str) -> Option<Vec<(String, &str)>> {
+    let mut words = line.split_whitespace().peekable();
+    let label = words.next_if(|word| !word.contains('=')).map(str::to_lowercase);
+    let mut settings = Vec::new();
+    for word in words {
+        let (key, value) = word.split_once('=')?;
+        let key = key.to_lowercase();
+        match &label {
+            _ if key.is_empty() => {}
+            Some(label) if !key.starts_with(label.as_str()) => {
+                settings.push((format!("{}_{}", label, key), value))
+            }
+            _ => settings.push((key, value)),
+        }
+    }
+    Some(settings)
+}
+
+/// The configuration read from the board, and when it was read.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct CachedConfig {
+    #[serde(flatten)]
+    pub config: BoardConfig,
+    /// When the configuration has been read from the board, in seconds since the Unix epoch.
+    pub fetched_at: u64,
+}
+
+/// The version of [`ConfigExport`] documents written by this service.
+pub const CONFIG_EXPORT_VERSION: u32 = 1;
+/// The settings the firmware takes as `key=value` messages and reports in `show_config`,
+/// the only ones a document may have.
+pub const SETTABLE_KEYS: &[&str] = &[
+    "pid_kp",
+    "pid_kd",
+    "pid_ki",
+    "pid_tstep",
+    "fan_check_time",
+    "pwm_freq",
+    "pwm_invert",
+    "min_pwm_speed",
+    "max_autofan_pwm",
+    "start_pwm_speed",
+    "wdt_push_time",
+    "wdt_wait_time",
+    "thermo_offset1",
+    "thermo_offset2",
+    "led_blink",
+    "fan_number",
+];
+/// The settings the firmware takes in JSON updates rather than messages. The board doesn't
+/// report them back, so they are never exported, but a document may have them.
+pub const UPDATE_KEYS: &[&str] = &[
+    "target_temp",
+    "target_mem",
+    "fan_mode",
+    "manual_fan_speed",
+    "watchdog",
+    "wd_reset_interval",
+];
+
+/// A backup of the board's settings.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ConfigExport {
+    /// The version of the document's format.
+    #[cfg_attr(feature = "openapi", schema(example = 1))]
+    pub version: u32,
+    /// When the settings have been read from the board, in seconds since the Unix epoch.
+    pub exported_at: u64,
+    /// The settable settings, as reported by `show_config`.
+    #[cfg_attr(
+        feature = "openapi",
+        schema(example = json!({"min_pwm_speed": "10", "fan_number": "6"}))
+    )]
+    pub settings: BTreeMap<String, String>,
+}
+
+impl ConfigExport {
+    /// Checks that the document can be imported: its version is known, and it has only
+    /// the settable settings, with integer values the board can be given.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.version > CONFIG_EXPORT_VERSION {
+            return Err(format!(
+                "Unsupported version {} of the document, the latest known is {}",
+                self.version, CONFIG_EXPORT_VERSION
+            ));
+        }
+        let unknown = self
+            .settings
+            .keys()
+            .map(String::as_str)
+            .filter(|key| !SETTABLE_KEYS.contains(key) && !UPDATE_KEYS.contains(key))
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            return Err(format!("Unknown settings: {}", unknown.join(", ")));
+        }
+        let invalid = self
+            .settings
+            .iter()
+            .filter(|(_, value)| value.parse::<i32>().is_err())
+            .map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>();
+        if !invalid.is_empty() {
+            return Err(format!("Settings without an integer value: {}", invalid.join(", ")));
+        }
+        self.update(&Update::default()).map(|_| ())
+    }
+
+    /// The settings of the document sent in a JSON update, put over those of `base`,
+    /// or `None` if the document has none.
+    pub fn update(&self, base: &Update) -> Result<Option<Update>, String> {
+        let mut update = match serde_json::to_value(base) {
+            Ok(serde_json::Value::Object(update)) => update,
+            _ => return Err("Unable to serialize the update".to_string()),
+        };
+        let mut changed = false;
+        for (key, value) in &self.settings {
+            if UPDATE_KEYS.contains(&key.as_str()) {
+                let value = value.parse::<i64>().map_err(|e| format!("{}: {}", key, e))?;
+                update.insert(key.clone(), value.into());
+                changed = true;
+            }
+        }
+        if !changed {
+            return Ok(None);
+        }
+        serde_json::from_value(update.into())
+            .map(Some)
+            .map_err(|e| format!("Invalid settings: {}", e))
+    }
+}
+
+/// What has become of a setting during an import.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ImportedSetting {
+    pub key: String,
+    /// The value from the imported document.
+    pub requested: String,
+    /// The value read back from the board afterwards, if the board reports the setting.
+    /// The settings sent in an update are not reported.
+    pub actual: Option<String>,
+    /// What the board has replied to the setting's message.
+    pub reply: Option<String>,
+    /// Why the setting's message has failed.
+    pub error: Option<String>,
+}
+
+impl ImportedSetting {
+    /// Whether the board has the requested value. The settings sent in an update can't be
+    /// read back, so they count as taken unless sending them has failed.
+    pub fn matches(&self) -> bool {
+        match UPDATE_KEYS.contains(&self.key.as_str()) {
+            true => self.error.is_none(),
+            false => self.actual.as_ref() == Some(&self.requested),
+        }
+    }
+}
+
+/// The result of an import: every setting requested, and the value the board has ended up with.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ConfigImportReport {
+    /// Whether the board has ended up with all the requested settings.
+    pub ok: bool,
+    /// The settings which differ from the requested ones.
+    pub mismatches: Vec<ImportedSetting>,
+    /// All the requested settings.
+    pub settings: Vec<ImportedSetting>,
+    /// The configuration read back from the board.
+    pub config: CachedConfig,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::FanMode;
+
+    /// The reply of the firmware 1271 to `show_config`.
+    const SHOW_CONFIG: &str = "fw_version=1171 \n\
+                               PID pid_kp=10 pid_kd=5 pid_ki=1 pid_tstep=2 \n\
+                               fan_check_time=5 \n\
+                               pwm_frequency=25 \n\
+                               pwm_invert=0 \n\
+                               min_pwm_speed=10 \n\
+                               max_autofan_pwm=100 \n\
+                               start_pwm_speed=70 \n\
+                               wdt_push_time=5 \n\
+                               wdt_wait_time=10 \n\
+                               thermo_offset1=0 \n\
+                               thermo_offset2=-1 \n\
+                               led_blink=1 \n\
+                               OSCCAL diff=-2 OSCCAL=146 \n\
+                               fan_number=6 \n\n";
+
+    #[test]
+    fn parses_show_config() {
+        assert_eq!(
+            BoardConfig::parse(SHOW_CONFIG),
+            BoardConfig {
+                fw_version: Some(1171),
+                pid_kp: Some(10),
+                pid_kd: Some(5),
+                pid_ki: Some(1),
+                pid_tstep: Some(2),
+                fan_check_time: Some(5),
+                pwm_frequency: Some(25),
+                pwm_invert: Some(false),
+                min_pwm_speed: Some(10),
+                max_autofan_pwm: Some(100),
+                start_pwm_speed: Some(70),
+                wdt_push_time: Some(5),
+                wdt_wait_time: Some(10),
+                thermo_offset1: Some(0),
+                thermo_offset2: Some(-1),
+                led_blink: Some(true),
+                osccal_diff: Some(-2),
+                osccal: Some(146),
+                fan_number: Some(6),
+                extra: BTreeMap::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_and_unexpected_settings() {
+        let config = BoardConfig::parse(
+            "config:\nPID pid_kp=12 pid_kd=fast \nfan_number=6 sensors=2\n\
+             OSCCAL diff=-2 step=1 \n=5\nset min fan speed min_pwm_speed=20 \n",
+        );
+        assert_eq!(config.pid_kp, Some(12));
+        assert_eq!(config.pid_kd, None);
+        assert_eq!(config.fan_number, Some(6));
+        assert_eq!(config.osccal_diff, Some(-2));
+        assert_eq!(config.min_pwm_speed, None);
+        assert_eq!(
+            config.extra,
+            BTreeMap::from([
+                ("osccal_step".to_string(), "1".to_string()),
+                ("pid_kd".to_string(), "fast".to_string()),
+                ("sensors".to_string(), "2".to_string()),
+            ])
+        );
+    }
+
+    fn export(settings: &[(&str, &str)]) -> ConfigExport {
+        ConfigExport {
+            version: CONFIG_EXPORT_VERSION,
+            exported_at: 1760000000,
+            settings: settings
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn exports_only_settable_settings() {
+        let config = BoardConfig::parse("fw_version=1171 \npwm_frequency=25 \nled_blink=1 \n");
+        assert_eq!(
+            config.to_settings(),
+            BTreeMap::from([
+                ("led_blink".to_string(), "1".to_string()),
+                ("pwm_freq".to_string(), "25".to_string()),
+            ])
+        );
+        let mut keys = SETTABLE_KEYS.to_vec();
+        keys.sort();
+        assert!(BoardConfig::parse(SHOW_CONFIG).to_settings().keys().eq(keys));
+    }
+
+    #[test]
+    fn validates_imported_documents() {
+        assert_eq!(export(&[("min_pwm_speed", "20"), ("pid_kp", "12")]).validate(), Ok(()));
+        assert_eq!(
+            export(&[("osccal", "150"), ("min_pwm_speed", "20"), ("pwm_frequency", "25")])
+                .validate(),
+            Err("Unknown settings: osccal, pwm_frequency".to_string())
+        );
+        assert_eq!(
+            export(&[("min_pwm_speed", "slow"), ("fan_number", "")]).validate(),
+            Err("Settings without an integer value: fan_number, min_pwm_speed".to_string())
+        );
+        let updates = export(&[("target_temp", "65"), ("fan_mode", "1"), ("pwm_freq", "25")]);
+        assert_eq!(updates.validate(), Ok(()));
+        assert!(export(&[("fan_mode", "7")]).validate().is_err());
+        assert!(export(&[("manual_fan_speed", "300")]).validate().is_err());
+        let future = ConfigExport {
+            version: CONFIG_EXPORT_VERSION + 1,
+            ..export(&[])
+        };
+        assert!(future.validate().is_err());
+    }
+
+    #[test]
+    fn puts_updates_over_the_desired_state() {
+        let desired = Update {
+            gpu_temp: Some(vec![60]),
+            target_temp: Some(70),
+            fan_mode: Some(FanMode::Manual),
+            manual_fan_speed: Some(40),
+            ..Default::default()
+        };
+        let document = export(&[("target_temp", "65"), ("fan_mode", "2"), ("watchdog", "1")]);
+        assert_eq!(
+            document.update(&desired),
+            Ok(Some(Update {
+                target_temp: Some(65),
+                fan_mode: Some(FanMode::Auto),
+                watchdog: Some(true),
+                ..desired.clone()
+            }))
+        );
+        assert_eq!(export(&[("min_pwm_speed", "20")]).update(&desired), Ok(None));
+    }
+
+    #[test]
+    fn reports_settings_not_taken() {
+        let setting = |requested: &str, actual: Option<&str>| ImportedSetting {
+            key: "min_pwm_speed".to_string(),
+            requested: requested.to_string(),
+            actual: actual.map(str::to_string),
+            reply: None,
+            error: None,
+        };
+        assert!(setting("20", Some("20")).matches());
+        assert!(!setting("20", Some("10")).matches());
+        assert!(!setting("20", None).matches());
+        let update = |error: Option<&str>| ImportedSetting {
+            key: "target_temp".to_string(),
+            error: error.map(str::to_string),
+            ..setting("65", None)
+        };
+        assert!(update(None).matches());
+        assert!(!update(Some("The port is closed")).matches());
+    }
+}
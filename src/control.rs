@@ -0,0 +1,506 @@
+//! Fan control done by the service instead of the firmware's auto mode.
+//!
+//! The firmware's auto mode only takes one core target and one VRAM target. A fan curve maps
+//! the hottest core and VRAM temperatures to a fan speed, which is then sent to the board
+//! in the manual mode, just like a `fan_speed` given to `/api/update`.
+//!
+//! Alternatively, a PID controller chooses the fan speed to keep the hottest GPU core and VRAM
+//! at the targets given to `/api/update`, instead of the firmware stepping the speed up and down.
+
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::{Command, FanMode, Update};
+use crate::telemetry::unix_time_now;
+
+/// Cycles coming faster than this, like when the last update is re-sent, don't change the speed.
+const MIN_PID_CYCLE_S: f64 = 0.5;
+
+/// A point of a fan curve: at this temperature the fans spin at this speed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct CurvePoint {
+    /// Temperature, °C.
+    #[cfg_attr(feature = "openapi", schema(example = 60))]
+    pub temp: i32,
+    /// Fan speed, in percent.
+    #[cfg_attr(feature = "openapi", schema(example = 50))]
+    pub speed: u8,
+}
+
+/// Piecewise-linear fan curves, for the GPU cores and the VRAM. Between the points the speed
+/// is interpolated, below the first point and above the last one it stays the same.
+/// The fans spin at the higher of the two speeds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct FanCurve {
+    /// Fan speed by the hottest GPU core, the points ordered by temperature.
+    pub core: Vec<CurvePoint>,
+    /// Fan speed by the hottest GPU VRAM, the points ordered by temperature.
+    /// If empty, the VRAM temperatures are ignored.
+    #[serde(default)]
+    pub mem: Vec<CurvePoint>,
+}
+
+/// A curve as given on the command line: `<temp>:<speed>,...[/<temp>:<speed>,...]`,
+/// the core curve first and, after a slash, the VRAM one.
+/// For instance `40:20,60:50,80:100/70:30,95:100`.
+impl std::str::FromStr for FanCurve {
+    type Err = String;
+
+    fn from_str(curve: &str) -> Result<Self, Self::Err> {
+        let parse_points = |points: &str| {
+            points
+                .split(',')
+                .map(str::trim)
+                .filter(|point| !point.is_empty())
+                .map(|point| {
+                    let (temp, speed) = point.split_once(':').ok_or_else(|| {
+                        format!("Invalid point {:?}, expected <temp>:<speed>", point)
+                    })?;
+                    Ok(CurvePoint {
+                        temp: temp
+                            .trim()
+                            .parse()
+                            .map_err(|_| format!("Invalid temperature {:?}", temp))?,
+                        speed: speed
+                            .trim()
+                            .parse()
+                            .map_err(|_| format!("Invalid speed {:?}", speed))?,
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>()
+        };
+        let (core, mem) = curve.split_once('/').unwrap_or((curve, ""));
+        let curve = Self {
+            core: parse_points(core)?,
+            mem: parse_points(mem)?,
+        };
+        curve.validate()?;
+        Ok(curve)
+    }
+}
+
+impl FanCurve {
+    /// Checks that the core curve has points, all the points are ordered by temperature,
+    /// and all the speeds are percents.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.core.is_empty() {
+            return Err("The core curve must have at least one point".into());
+        }
+        for (name, points) in [("core", &self.core), ("VRAM", &self.mem)] {
+            if let Some(point) = points.iter().find(|point| point.speed > 100) {
+                return Err(format!("The {} curve has a speed above 100%: {}", name, point.speed));
+            }
+            if points.windows(2).any(|pair| pair[0].temp >= pair[1].temp) {
+                return Err(format!(
+                    "The points of the {} curve must go in the order of increasing temperature",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The fan speed for the given temperatures, or nothing if there are no core temperatures.
+    pub fn speed(&self, core_temp: &[i32], mem_temp: &[i32]) -> Option<u8> {
+        let core_speed = interpolate(&self.core, *core_temp.iter().max()?)?;
+        let mem_speed = mem_temp
+            .iter()
+            .max()
+            .and_then(|&temp| interpolate(&self.mem, temp));
+        Some(core_speed.max(mem_speed.unwrap_or(0)))
+    }
+}
+
+fn interpolate(points: &[CurvePoint], temp: i32) -> Option<u8> {
+    let first = points.first()?;
+    let last = points.last()?;
+    if temp <= first.temp {
+        return Some(first.speed);
+    }
+    if temp >= last.temp {
+        return Some(last.speed);
+    }
+    let pair = points.windows(2).find(|pair| temp <= pair[1].temp)?;
+    let (low, high) = (pair[0], pair[1]);
+    let fraction = (temp - low.temp) as f64 / (high.temp - low.temp) as f64;
+    let speed = low.speed as f64 + fraction * (high.speed as f64 - low.speed as f64);
+    Some(speed.round() as u8)
+}
+
+/// The fan curve of a board and what it has decided last.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct FanCurveStatus {
+    /// The curve, if the service controls the fans. Otherwise the firmware does.
+    pub curve: Option<FanCurve>,
+    /// The fan speed last sent to the board, in percent.
+    pub last_speed: Option<u8>,
+    /// When the fan speed has been sent, in seconds since the Unix epoch.
+    pub last_applied_at: Option<u64>,
+}
+
+/// Settings of the PID controller.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(default)]
+pub struct PidSettings {
+    /// Proportional gain, % of fan speed per °C above the target.
+    #[cfg_attr(feature = "openapi", schema(example = 4.0))]
+    pub kp: f64,
+    /// Integral gain, % of fan speed per °C·s above the target.
+    #[cfg_attr(feature = "openapi", schema(example = 0.1))]
+    pub ki: f64,
+    /// Derivative gain, % of fan speed per °C/s of the error growing.
+    #[cfg_attr(feature = "openapi", schema(example = 0.0))]
+    pub kd: f64,
+    /// The slowest the fans may spin, in percent.
+    #[cfg_attr(feature = "openapi", schema(example = 20))]
+    pub min_speed: u8,
+    /// The fastest the fans may spin, in percent.
+    #[cfg_attr(feature = "openapi", schema(example = 100))]
+    pub max_speed: u8,
+    /// How fast the fan speed may change, in percent per second.
+    #[cfg_attr(feature = "openapi", schema(example = 5.0))]
+    pub max_step: f64,
+}
+
+impl Default for PidSettings {
+    fn default() -> Self {
+        Self {
+            kp: 4.0,
+            ki: 0.1,
+            kd: 0.0,
+            min_speed: 20,
+            max_speed: 100,
+            max_step: 5.0,
+        }
+    }
+}
+
+/// Settings as given on the command line: comma-separated `<name>=<value>`, like `kp=4,ki=0.1`.
+/// The settings not given keep their default values.
+impl std::str::FromStr for PidSettings {
+    type Err = String;
+
+    fn from_str(settings: &str) -> Result<Self, Self::Err> {
+        let mut parsed = Self::default();
+        for setting in settings.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid setting {:?}, expected <name>=<value>", setting))?;
+            let invalid = || format!("Invalid value of {}: {:?}", name.trim(), value);
+            let value = value.trim();
+            match name.trim() {
+                "kp" => parsed.kp = value.parse().map_err(|_| invalid())?,
+                "ki" => parsed.ki = value.parse().map_err(|_| invalid())?,
+                "kd" => parsed.kd = value.parse().map_err(|_| invalid())?,
+                "min_speed" => parsed.min_speed = value.parse().map_err(|_| invalid())?,
+                "max_speed" => parsed.max_speed = value.parse().map_err(|_| invalid())?,
+                "max_step" => parsed.max_step = value.parse().map_err(|_| invalid())?,
+                name => return Err(format!("Unknown PID setting {:?}", name)),
+            }
+        }
+        parsed.validate()?;
+        Ok(parsed)
+    }
+}
+
+impl PidSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if ![self.kp, self.ki, self.kd, self.max_step]
+            .iter()
+            .all(|value| value.is_finite() && *value >= 0.0)
+        {
+            return Err("The gains and the step must be non-negative numbers".into());
+        }
+        if self.max_step == 0.0 {
+            return Err("The step must be above 0, or the speed would never change".into());
+        }
+        if self.min_speed > self.max_speed || self.max_speed > 100 {
+            return Err(format!(
+                "The speeds must be within 0-100%, the minimal one not above the maximal one: {}-{}",
+                self.min_speed, self.max_speed
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// What the PID controller has computed in its last cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PidState {
+    /// How much the hottest GPU core or VRAM is above its target, °C. Negative if below.
+    pub error: f64,
+    /// The accumulated error, °C·s.
+    pub integral: f64,
+    /// How fast the error grows, °C/s.
+    pub derivative: f64,
+    /// The sum of the terms, before the clamping and the rate limit.
+    pub output: f64,
+    /// The fan speed sent to the board, in percent.
+    pub speed: f64,
+    /// When the cycle has happened, in seconds since the Unix epoch.
+    pub updated_at: u64,
+}
+
+/// Keeps the hottest GPU at its target temperature by choosing the fan speed.
+/// The integral stops growing while the speed is held back by the limits (anti-windup).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PidController {
+    settings: PidSettings,
+    state: Option<PidState>,
+    last_cycle: Option<Instant>,
+}
+
+impl PidController {
+    pub fn new(settings: PidSettings) -> Self {
+        Self {
+            settings,
+            state: None,
+            last_cycle: None,
+        }
+    }
+
+    pub fn settings(&self) -> &PidSettings {
+        &self.settings
+    }
+
+    pub fn state(&self) -> Option<&PidState> {
+        self.state.as_ref()
+    }
+
+    /// Runs a cycle for the error measured now, returning the fan speed.
+    pub fn cycle(&mut self, error: f64) -> f64 {
+        let now = Instant::now();
+        let elapsed = self.last_cycle.map(|last| (now - last).as_secs_f64());
+        if let (Some(state), Some(elapsed)) = (self.state, elapsed)
+            && elapsed < MIN_PID_CYCLE_S
+        {
+            return state.speed;
+        }
+        self.last_cycle = Some(now);
+        self.step(error, elapsed)
+    }
+
+    /// Computes the fan speed for the error, `elapsed` seconds after the previous step.
+    fn step(&mut self, error: f64, elapsed: Option<f64>) -> f64 {
+        let settings = &self.settings;
+        let (min_speed, max_speed) = (settings.min_speed as f64, settings.max_speed as f64);
+        let previous = self.state.zip(elapsed);
+        let (integral, derivative) = match previous {
+            Some((state, elapsed)) => (
+                state.integral + error * elapsed,
+                (error - state.error) / elapsed,
+            ),
+            None => (0.0, 0.0),
+        };
+        let terms = |integral: f64| {
+            settings.kp * error + settings.ki * integral + settings.kd * derivative
+        };
+        let limit = |output: f64| {
+            let speed = output.clamp(min_speed, max_speed);
+            match previous {
+                Some((state, elapsed)) => {
+                    let step = settings.max_step * elapsed;
+                    speed.clamp(state.speed - step, state.speed + step)
+                }
+                None => speed,
+            }
+        };
+        let mut integral = integral;
+        let mut output = terms(integral);
+        let speed = limit(output);
+        // Anti-windup: the integral only grows as far as the speed can follow
+        if settings.ki > 0.0 && ((output > speed && error > 0.0) || (output < speed && error < 0.0))
+        {
+            let previous_integral = previous.map(|(state, ..)| state.integral).unwrap_or(0.0);
+            let reachable = (speed - settings.kp * error - settings.kd * derivative) / settings.ki;
+            integral = if error > 0.0 {
+                reachable.max(previous_integral)
+            } else {
+                reachable.min(previous_integral)
+            };
+            output = terms(integral);
+        }
+        self.state = Some(PidState {
+            error,
+            integral,
+            derivative,
+            output,
+            speed,
+            updated_at: unix_time_now(),
+        });
+        speed
+    }
+}
+
+/// The PID controller of a board, as shown by the API.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PidStatus {
+    /// The settings, if the PID controller drives the fans.
+    pub settings: Option<PidSettings>,
+    /// The last cycle, if there has been any.
+    pub state: Option<PidState>,
+}
+
+/// How the service drives the fans of a board, if it does: through a fan curve or
+/// a PID controller. Setting one of them removes the other.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FanControl {
+    pub curve: FanCurveStatus,
+    pub pid: Option<PidController>,
+}
+
+impl FanControl {
+    pub fn pid_status(&self) -> PidStatus {
+        PidStatus {
+            settings: self.pid.as_ref().map(|pid| *pid.settings()),
+            state: self.pid.as_ref().and_then(|pid| pid.state().copied()),
+        }
+    }
+
+    /// What to send to the board instead of the desired state: if the desired state leaves
+    /// the fans to the firmware's auto mode and has the temperatures, the fan curve or the PID
+    /// controller chooses the speed. Otherwise the desired state is sent as is.
+    pub fn apply(&mut self, command: &Command) -> Command {
+        let Command::Update(update) = command else {
+            return command.clone();
+        };
+        if update.fan_mode == Some(FanMode::Manual) {
+            return command.clone();
+        }
+        let core_temp = update.gpu_temp.as_deref().unwrap_or_default();
+        let mem_temp = update.gpu_mem.as_deref().unwrap_or_default();
+        let speed = if let Some(pid) = &mut self.pid {
+            let core_error = core_temp.iter().max().zip(update.target_temp);
+            let mem_error = mem_temp.iter().max().zip(update.target_mem);
+            let error = [core_error, mem_error]
+                .into_iter()
+                .flatten()
+                .map(|(temp, target)| (temp - target) as f64)
+                .reduce(f64::max);
+            error.map(|error| pid.cycle(error).round() as u8)
+        } else if let Some(curve) = &self.curve.curve {
+            let speed = curve.speed(core_temp, mem_temp);
+            if speed.is_some() {
+                self.curve.last_speed = speed;
+                self.curve.last_applied_at = Some(unix_time_now());
+            }
+            speed
+        } else {
+            None
+        };
+        match speed {
+            Some(speed) => Update {
+                fan_mode: Some(FanMode::Manual),
+                manual_fan_speed: Some(speed),
+                ..update.clone()
+            }
+            .into(),
+            None => command.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_between_points() {
+        let curve = "40:20,60:50,80:100/70:30,90:100".parse::<FanCurve>().unwrap();
+        assert_eq!(curve.speed(&[30], &[]), Some(20));
+        assert_eq!(curve.speed(&[45, 50], &[]), Some(35));
+        assert_eq!(curve.speed(&[70], &[]), Some(75));
+        assert_eq!(curve.speed(&[95], &[]), Some(100));
+        // The hot VRAM wins over the cool cores
+        assert_eq!(curve.speed(&[40, 41], &[60, 80]), Some(65));
+        assert_eq!(curve.speed(&[], &[80]), None);
+    }
+
+    #[test]
+    fn rejects_invalid_curves() {
+        assert!("".parse::<FanCurve>().is_err());
+        assert!("60:50,40:20".parse::<FanCurve>().is_err());
+        assert!("40:20,60:150".parse::<FanCurve>().is_err());
+        assert!("40-20".parse::<FanCurve>().is_err());
+    }
+
+    #[test]
+    fn switches_to_manual_speed() {
+        let mut control = FanControl::default();
+        control.curve.curve = Some("40:20,80:100".parse::<FanCurve>().unwrap());
+        let update = Update::for_temperatures(vec![60], vec![], 70, 90, None, None);
+        assert_eq!(
+            control.apply(&update.clone().into()),
+            Command::Update(Update {
+                fan_mode: Some(FanMode::Manual),
+                manual_fan_speed: Some(60),
+                ..update
+            })
+        );
+        assert_eq!(control.curve.last_speed, Some(60));
+        let manual: Command =
+            Update::for_temperatures(vec![60], vec![], 70, 90, None, Some(30)).into();
+        assert_eq!(control.apply(&manual), manual);
+    }
+
+    #[test]
+    fn pid_clamps_and_limits_rate() {
+        let mut pid = PidController::new(PidSettings {
+            kp: 10.0,
+            ki: 0.0,
+            max_step: 2.0,
+            ..Default::default()
+        });
+        assert_eq!(pid.step(-5.0, None), 20.0);
+        // Wants 100%, but may only go 2% per second faster
+        assert_eq!(pid.step(10.0, Some(5.0)), 30.0);
+        assert_eq!(pid.step(10.0, Some(100.0)), 100.0);
+        assert_eq!(pid.state().unwrap().output, 100.0);
+    }
+
+    #[test]
+    fn pid_integral_does_not_wind_up() {
+        let mut pid = PidController::new(PidSettings {
+            kp: 1.0,
+            ki: 1.0,
+            max_speed: 50,
+            ..Default::default()
+        });
+        pid.step(10.0, None);
+        for _ in 0..10 {
+            pid.step(10.0, Some(10.0));
+        }
+        // Held at the maximum, the integral stops growing...
+        assert_eq!(pid.state().unwrap().speed, 50.0);
+        assert!(pid.state().unwrap().integral <= 50.0);
+        // ...so the fans slow down as soon as the GPU is below its target
+        pid.step(-5.0, Some(1.0));
+        assert!(pid.state().unwrap().speed < 50.0);
+    }
+
+    #[test]
+    fn pid_targets_hottest_gpu() {
+        let mut control = FanControl {
+            pid: Some(PidController::new(PidSettings {
+                kp: 5.0,
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let update = Update::for_temperatures(vec![60, 72], vec![80, 95], 70, 90, None, None);
+        let Command::Update(controlled) = control.apply(&update.into()) else {
+            panic!("An update must stay an update");
+        };
+        assert_eq!(controlled.fan_mode, Some(FanMode::Manual));
+        // VRAM is 5°C above its target, more than the cores
+        assert_eq!(controlled.manual_fan_speed, Some(25));
+        assert_eq!(control.pid_status().state.unwrap().error, 5.0);
+    }
+}
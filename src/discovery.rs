@@ -0,0 +1,167 @@
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use serialport::{SerialPortInfo, SerialPortType};
+
+use super::autofan::open_coolbox_autofan_port;
+use super::command::Command;
+use super::identity::BoardIdentity;
+
+/// A harmless command every known firmware replies to.
+pub const PROBE_COMMAND: Command = Command::Diagnostic;
+pub const PROBE_TIMEOUT_MS: u64 = 2000;
+
+/// Fields of the Coolbox firmware's reply to the probe, each holding a version number.
+/// Any other device is unlikely to reply with both.
+const COOLBOX_VERSION_FIELDS: &[&str] = &["pcb_version", "fw_version"];
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DiscoveredPort {
+    /// Path of the serial port.
+    #[cfg_attr(feature = "openapi", schema(example = "/dev/ttyUSB0"))]
+    pub port: String,
+    /// What is known about the USB-serial adapter behind the port. Useful for
+    /// binding a board id to the adapter rather than to the port's path.
+    pub identity: BoardIdentity,
+    /// Id of the board already served through this port, if any. Such ports are not probed.
+    pub board_id: Option<String>,
+    /// Whether the port looks like it's connected to a Coolbox Autofan board.
+    pub is_coolbox: bool,
+    /// What the device replied to the probe.
+    pub reply: Option<String>,
+    /// Why the port couldn't be probed.
+    pub error: Option<String>,
+}
+
+/// Lists the serial ports a Coolbox board can be attached to. The board is always connected
+/// through a USB-serial adapter, so the built-in serial ports are skipped.
+pub fn candidate_ports() -> io::Result<Vec<SerialPortInfo>> {
+    let ports = serialport::available_ports().map_err(io::Error::other)?;
+    Ok(ports
+        .into_iter()
+        .filter(|port| matches!(port.port_type, SerialPortType::UsbPort(..)))
+        .collect())
+}
+
+/// Guesses whether the reply has come from a Coolbox firmware: it has to be a JSON object
+/// reporting both the PCB and the firmware versions, like
+/// `{"mcu_version":"m328p","pcb_version":1031,"fw_version":1271,"msg_errors":0,"reboot_errors":0}`.
+pub fn looks_like_coolbox(reply: &str) -> bool {
+    let Ok(serde_json::Value::Object(reply)) = serde_json::from_str(reply.trim()) else {
+        return false;
+    };
+    COOLBOX_VERSION_FIELDS
+        .iter()
+        .all(|field| reply.get(*field).is_some_and(|version| version.as_u64().is_some()))
+}
+
+/// Opens the port with the board's settings, sends the probe command
+/// and collects whatever the device replies.
+pub fn probe_port(port_path: &str) -> io::Result<String> {
+    let mut port = open_coolbox_autofan_port(port_path).map_err(io::Error::other)?;
+    port.write_all(&PROBE_COMMAND.encode())?;
+    let reply_spec = PROBE_COMMAND.reply_spec();
+    let mut framer = reply_spec.framer();
+    let deadline = Instant::now() + Duration::from_millis(PROBE_TIMEOUT_MS);
+    let mut buffer = [0u8; 256];
+    while Instant::now() < deadline {
+        match port.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(bytes) => {
+                if buffer[..bytes].iter().any(|&byte| framer.feed_byte(byte)) {
+                    break;
+                }
+            }
+            // The device has stopped talking
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut && !framer.is_empty() => break,
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(String::from_utf8_lossy(&framer.into_reply()).to_string())
+}
+
+/// Probes all candidate ports in parallel. The ports from `managed_ports`
+/// (pairs of a port path and a board id) are already in use and reported without probing.
+pub fn discover(managed_ports: &[(String, String)]) -> io::Result<Vec<DiscoveredPort>> {
+    let candidates = candidate_ports()?;
+    let discovered = std::thread::scope(|scope| {
+        let probes = candidates
+            .into_iter()
+            .map(|port_info| {
+                let identity = BoardIdentity::from_port_info(&port_info);
+                let port = port_info.port_name;
+                let board_id = managed_ports
+                    .iter()
+                    .find(|(managed_port, _)| *managed_port == port)
+                    .map(|(_, board_id)| board_id.clone());
+                scope.spawn(move || {
+                    if board_id.is_some() {
+                        return DiscoveredPort {
+                            port,
+                            identity,
+                            board_id,
+                            is_coolbox: true,
+                            reply: None,
+                            error: None,
+                        };
+                    }
+                    match probe_port(&port) {
+                        Ok(reply) => DiscoveredPort {
+                            is_coolbox: looks_like_coolbox(&reply),
+                            port,
+                            identity,
+                            board_id,
+                            reply: Some(reply),
+                            error: None,
+                        },
+                        Err(e) => DiscoveredPort {
+                            port,
+                            identity,
+                            board_id,
+                            is_coolbox: false,
+                            reply: None,
+                            error: Some(e.to_string()),
+                        },
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        probes
+            .into_iter()
+            .filter_map(|probe| probe.join().ok())
+            .collect::<Vec<_>>()
+    });
+    for port in &discovered {
+        log::debug!("Probed {}: {:?}", port.port, port);
+    }
+    Ok(discovered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::DIAGNOSTIC_REPLY;
+
+    #[test]
+    fn recognizes_coolbox_diagnostic() {
+        assert!(looks_like_coolbox(DIAGNOSTIC_REPLY));
+        assert!(looks_like_coolbox(&format!("\r\n{}\r\n", DIAGNOSTIC_REPLY.trim_end())));
+        assert!(looks_like_coolbox(r#"{"pcb_version":1040,"fw_version":1300}"#));
+    }
+
+    #[test]
+    fn rejects_other_devices() {
+        // Sensors and microcontrollers talking about fans and temperatures
+        assert!(!looks_like_coolbox("fan speed 1200 rpm\ntemp 45.5\n"));
+        assert!(!looks_like_coolbox("{\"temp\":45,\"fan\":1}"));
+        assert!(!looks_like_coolbox("Autofan controller ready. Firmware v2.1\n"));
+        // Only one of the versions, or not a version at all
+        assert!(!looks_like_coolbox(r#"{"mcu_version":"m328p","fw_version":1271}"#));
+        assert!(!looks_like_coolbox(r#"{"pcb_version":"1031h","fw_version":1271}"#));
+        assert!(!looks_like_coolbox(&format!("error: {} expected\n", DIAGNOSTIC_REPLY)));
+        assert!(!looks_like_coolbox("pcb_version=1031\nfw_version=1271\n"));
+        assert!(!looks_like_coolbox(""));
+    }
+}
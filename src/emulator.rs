@@ -0,0 +1,556 @@
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use serialport::{SerialPort, TTYPort};
+
+use crate::framing::JsonObjectFramer;
+use crate::simulation::SharedThermalPlant;
+
+/// The firmware reports its version as `12` and this number in the diagnostic,
+/// and, for some reason, as `11` and this number in `show_config`.
+pub const FIRMWARE_SUBVERSION: u32 = 71;
+pub const PCB_VERSION: u32 = 1031;
+pub const TICK_INTERVAL_MS: u64 = 1000;
+const OCR0A_MAX: i32 = 200;
+const MAX_PWM_STEP: i32 = 5;
+
+/// Settings the firmware keeps and reports with `show_config`, each set by a message
+/// like `min_pwm_speed=20`. The defaults are the emulator's own.
+#[derive(Debug, Clone, PartialEq)]
+struct Settings {
+    pid_kp: i32,
+    pid_kd: i32,
+    pid_ki: i32,
+    pid_tstep: i32,
+    fan_check_time: i32,
+    pwm_frequency: i32,
+    pwm_invert: i32,
+    min_pwm_speed: i32,
+    max_autofan_pwm: i32,
+    start_pwm_speed: i32,
+    wdt_push_time: i32,
+    wdt_wait_time: i32,
+    thermo_offset1: i32,
+    thermo_offset2: i32,
+    led_blink: i32,
+    osccal_diff: i32,
+    osccal: i32,
+    fan_number: i32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            pid_kp: 10,
+            pid_kd: 5,
+            pid_ki: 1,
+            pid_tstep: 2,
+            fan_check_time: 5,
+            pwm_frequency: 25,
+            pwm_invert: 0,
+            min_pwm_speed: 10,
+            max_autofan_pwm: 100,
+            start_pwm_speed: 70,
+            wdt_push_time: 5,
+            wdt_wait_time: 10,
+            thermo_offset1: 0,
+            thermo_offset2: 0,
+            led_blink: 1,
+            osccal_diff: -2,
+            osccal: 146,
+            fan_number: 6,
+        }
+    }
+}
+
+impl Settings {
+    /// The reply to `show_config`, line by line as the firmware prints it.
+    fn config_text(&self) -> String {
+        format!(
+            "fw_version=11{} \n\
+             PID pid_kp={} pid_kd={} pid_ki={} pid_tstep={} \n\
+             fan_check_time={} \n\
+             pwm_frequency={} \n\
+             pwm_invert={} \n\
+             min_pwm_speed={} \n\
+             max_autofan_pwm={} \n\
+             start_pwm_speed={} \n\
+             wdt_push_time={} \n\
+             wdt_wait_time={} \n\
+             thermo_offset1={} \n\
+             thermo_offset2={} \n\
+             led_blink={} \n\
+             OSCCAL diff={} OSCCAL={} \n\
+             fan_number={} \n",
+            FIRMWARE_SUBVERSION,
+            self.pid_kp,
+            self.pid_kd,
+            self.pid_ki,
+            self.pid_tstep,
+            self.fan_check_time,
+            self.pwm_frequency,
+            self.pwm_invert,
+            self.min_pwm_speed,
+            self.max_autofan_pwm,
+            self.start_pwm_speed,
+            self.wdt_push_time,
+            self.wdt_wait_time,
+            self.thermo_offset1,
+            self.thermo_offset2,
+            self.led_blink,
+            self.osccal_diff,
+            self.osccal,
+            self.fan_number,
+        )
+    }
+
+    /// Takes the setting from a message. Returns the firmware's reply,
+    /// or `None` if the firmware ignores the message.
+    fn set(&mut self, key: &str, value: i32) -> Option<String> {
+        let flag = |value: i32| (value != 0) as i32;
+        let reply = match key {
+            "pwm_freq" => {
+                self.pwm_frequency = value;
+                format!("pwm frequency = {} khz \n", value)
+            }
+            "fan_check_time" => {
+                self.fan_check_time = value;
+                format!("set fan_check_time={} \n", value)
+            }
+            "pwm_invert" => {
+                self.pwm_invert = flag(value);
+                if self.pwm_invert == 1 {
+                    "using inverted pwm (use this option when your fans actually works \
+                     on max speed when you set 0% speed)  \n"
+                        .into()
+                } else {
+                    "using non-inverted pwm \n".into()
+                }
+            }
+            "led_blink" => {
+                self.led_blink = flag(value);
+                if self.led_blink == 1 {
+                    "blinking LED (default) \n".into()
+                } else {
+                    "turning off LED \n".into()
+                }
+            }
+            "thermo_offset1" => {
+                self.thermo_offset1 = value;
+                format!("set thermo_offset1={} \n", value)
+            }
+            "thermo_offset2" => {
+                self.thermo_offset2 = value;
+                format!("set thermo_offset2={} \n", value)
+            }
+            "start_pwm_speed" => {
+                self.start_pwm_speed = value;
+                format!("set pwm speed after a PC boot start_pwm_speed={} \n", value)
+            }
+            "max_autofan_pwm" => {
+                self.max_autofan_pwm = value;
+                format!("set max autofan speed max_autofan_pwm={} \n", value)
+            }
+            "min_pwm_speed" => {
+                self.min_pwm_speed = value;
+                format!("set min fan speed min_pwm_speed={} \n", value)
+            }
+            "wdt_wait_time" => {
+                self.wdt_wait_time = value;
+                format!("set time after PC shutdown and before PC wake wdt_wait_time={} \n", value)
+            }
+            "fan_number" => {
+                self.fan_number = value;
+                format!("set number of fans fan_number={} \n", value)
+            }
+            "wdt_push_time" => {
+                self.wdt_push_time = value;
+                format!("set POWER_BTN push time wdt_push_time={} \n", value)
+            }
+            "pid_kp" | "pid_kd" | "pid_ki" | "pid_tstep" => {
+                match key {
+                    "pid_kp" => self.pid_kp = value,
+                    "pid_kd" => self.pid_kd = value,
+                    "pid_ki" => self.pid_ki = value,
+                    _ => self.pid_tstep = value,
+                }
+                format!(
+                    "set PID coeffs pid_kp={} pid_kd={} pid_ki={} pid_time={} \n",
+                    self.pid_kp, self.pid_kd, self.pid_ki, self.pid_tstep
+                )
+            }
+            _ => return None,
+        };
+        Some(reply)
+    }
+}
+
+/// Settings taken from the JSON updates, which `show_config` doesn't report.
+#[derive(Debug, Clone, PartialEq)]
+struct UpdateSettings {
+    target_temp: i32,
+    target_mem: i32,
+    /// 1 - manual, 2 - automatic
+    fan_mode: i32,
+    manual_fan_speed: i32,
+    watchdog: i32,
+    /// In minutes
+    wd_reset_interval: i32,
+}
+
+impl Default for UpdateSettings {
+    fn default() -> Self {
+        Self {
+            target_temp: 60,
+            target_mem: 90,
+            fan_mode: 2,
+            manual_fan_speed: 70,
+            watchdog: 0,
+            wd_reset_interval: 5,
+        }
+    }
+}
+
+impl UpdateSettings {
+    fn set(&mut self, key: &str, value: i32) {
+        match key {
+            "target_temp" => self.target_temp = value,
+            "target_mem" => self.target_mem = value,
+            "fan_mode" => self.fan_mode = value,
+            "manual_fan_speed" => self.manual_fan_speed = value,
+            "watchdog" => self.watchdog = value,
+            "wd_reset_interval" => self.wd_reset_interval = value,
+            _ => {}
+        }
+    }
+}
+
+/// A software model of the Coolbox Autofan firmware 1271, as seen through its serial port.
+/// The replies are those of the firmware: JSON updates and `fan_check` get none,
+/// neither do the commands the firmware can't make sense of, and every text reply
+/// is followed by an empty line.
+struct Firmware {
+    settings: Settings,
+    update_settings: UpdateSettings,
+    service_mode: bool,
+    gpu_temp: Vec<i32>,
+    gpu_mem: Vec<i32>,
+    fan_pwm: i32,
+    pwm_add: i32,
+    cnt: i32,
+    last_update: Instant,
+}
+
+impl Default for Firmware {
+    fn default() -> Self {
+        let update_settings = UpdateSettings::default();
+        Self {
+            fan_pwm: update_settings.manual_fan_speed,
+            settings: Settings::default(),
+            update_settings,
+            service_mode: false,
+            gpu_temp: Vec::new(),
+            gpu_mem: Vec::new(),
+            pwm_add: 0,
+            cnt: 0,
+            last_update: Instant::now(),
+        }
+    }
+}
+
+impl Firmware {
+    /// Handles the command. Returns the reply, which is empty if the firmware gives none.
+    fn handle_command(&mut self, command: &[u8]) -> String {
+        let Ok(serde_json::Value::Object(command)) = serde_json::from_slice(command) else {
+            return String::new();
+        };
+        if command.contains_key("diagnostic") {
+            return self.diagnostic_text();
+        }
+        if command.contains_key("fan_check") {
+            return String::new();
+        }
+        if let Some(message) = command.get("message") {
+            return match message.as_str().and_then(|message| self.handle_message(message)) {
+                Some(reply) => format!("{}\n", reply),
+                None => String::new(),
+            };
+        }
+        let as_temps = |value: &serde_json::Value| -> Vec<i32> {
+            value
+                .as_array()
+                .map(|temps| {
+                    temps
+                        .iter()
+                        .filter_map(|t| t.as_i64())
+                        .map(|t| t as i32)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        for (key, value) in &command {
+            match key.as_str() {
+                "gpu_temp" => self.gpu_temp = as_temps(value),
+                "gpu_mem" => self.gpu_mem = as_temps(value),
+                _ => {
+                    if let Some(value) = value.as_i64() {
+                        self.update_settings.set(key, value as i32);
+                    }
+                }
+            }
+        }
+        self.last_update = Instant::now();
+        String::new()
+    }
+
+    /// Handles a text message, returning the reply without the empty line after it.
+    fn handle_message(&mut self, message: &str) -> Option<String> {
+        match message.trim() {
+            "service_mode=1" => {
+                self.service_mode = true;
+                Some("service mode ON \n".into())
+            }
+            "service_mode=0" => {
+                self.service_mode = false;
+                Some("service mode OFF  \n".into())
+            }
+            "show_config" => Some(self.settings.config_text()),
+            "default" => {
+                self.settings = Settings::default();
+                Some("settings was set to default  \n".into())
+            }
+            other => {
+                let (key, value) = other.split_once('=')?;
+                let value = value.trim().parse::<i32>().ok()?;
+                self.settings.set(key.trim(), value)
+            }
+        }
+    }
+
+    fn diagnostic_text(&self) -> String {
+        format!(
+            "{{\"mcu_version\":\"m328p\",\"pcb_version\":{},\"fw_version\":12{},\
+             \"msg_errors\":0,\"reboot_errors\":0}}\n",
+            PCB_VERSION, FIRMWARE_SUBVERSION,
+        )
+    }
+
+    /// Runs one step of the control loop. Returns whatever the firmware prints meanwhile.
+    fn tick(&mut self) -> String {
+        let settings = &self.update_settings;
+        let max_t = self.gpu_temp.iter().max().copied();
+        let max_mem_t = self.gpu_mem.iter().max().copied();
+        if settings.fan_mode == 1 {
+            self.pwm_add = 0;
+            self.fan_pwm = settings.manual_fan_speed;
+        } else {
+            let core_error = max_t.map(|t| t - settings.target_temp);
+            let mem_error = max_mem_t.map(|t| t - settings.target_mem);
+            self.pwm_add = match (core_error, mem_error) {
+                (None, None) => 0,
+                (core_error, mem_error) => core_error
+                    .unwrap_or(i32::MIN)
+                    .max(mem_error.unwrap_or(i32::MIN))
+                    .clamp(-MAX_PWM_STEP, MAX_PWM_STEP),
+            };
+            self.fan_pwm += self.pwm_add;
+        }
+        let min_speed = self.settings.min_pwm_speed;
+        self.fan_pwm = self.fan_pwm.clamp(min_speed, self.settings.max_autofan_pwm.max(min_speed));
+        self.cnt = self.cnt.wrapping_add(1);
+
+        if settings.watchdog != 0
+            && self.last_update.elapsed()
+                > Duration::from_secs(settings.wd_reset_interval.max(1) as u64 * 60)
+        {
+            // The real board would push the rig's power button here, without a word
+            self.last_update = Instant::now();
+        }
+        if self.service_mode {
+            self.telemetry_text()
+        } else {
+            String::new()
+        }
+    }
+
+    fn telemetry_text(&self) -> String {
+        let min_max = |temps: &[i32]| {
+            (
+                temps.iter().min().copied().unwrap_or(0),
+                temps.iter().max().copied().unwrap_or(0),
+            )
+        };
+        let (min_t, max_t) = min_max(&self.gpu_temp);
+        let (min_mem_t, max_mem_t) = min_max(&self.gpu_mem);
+        format!(
+            "OCR0B={} OCR0A(max)={} fan_pwm={} auto_mode={} min_t={} max_t={} targ_t={} pwm_add={} cnt={} osccal={} \n\
+             min_mem_t={} max_mem_t={} targ_mem_t={} \n\n",
+            self.fan_pwm * OCR0A_MAX / 100,
+            OCR0A_MAX,
+            self.fan_pwm,
+            (self.update_settings.fan_mode == 2) as i32,
+            min_t,
+            max_t,
+            self.update_settings.target_temp,
+            self.pwm_add,
+            self.cnt,
+            self.settings.osccal_diff,
+            min_mem_t,
+            max_mem_t,
+            self.update_settings.target_mem,
+        )
+    }
+}
+
+/// Plays the role of the board on the device's end of a pseudo-terminal pair,
+/// driving the fans of the simulated rig. Stops when the other end of the pair gets closed.
+fn emulator_thread(mut port: TTYPort, plant: SharedThermalPlant) -> io::Result<()> {
+    let mut firmware = Firmware::default();
+    let mut framer = JsonObjectFramer::default();
+    let mut buffer = [0u8; 256];
+    let mut next_tick = Instant::now() + Duration::from_millis(TICK_INTERVAL_MS);
+    port.set_timeout(Duration::from_millis(100))?;
+    loop {
+        match port.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(bytes) => {
+                for &byte in &buffer[..bytes] {
+                    if let Some(command) = framer.feed_byte(byte) {
+                        log::debug!("Emulator received {:?}", String::from_utf8_lossy(&command));
+                        let reply = firmware.handle_command(&command);
+                        port.write_all(reply.as_bytes())?;
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => {
+                log::debug!("Stopping the emulator: {}", e);
+                return Ok(());
+            }
+        }
+        if Instant::now() >= next_tick {
+            next_tick += Duration::from_millis(TICK_INTERVAL_MS);
+            let output = firmware.tick();
+            if let Ok(mut plant) = plant.lock() {
+                plant.step(TICK_INTERVAL_MS as f64 / 1000.0, firmware.fan_pwm);
+            }
+            if !output.is_empty() {
+                port.write_all(output.as_bytes())?;
+            }
+        }
+    }
+}
+
+/// Starts emulating the firmware behind the given port.
+pub fn spawn_emulator(
+    port: TTYPort,
+    plant: SharedThermalPlant,
+) -> std::thread::JoinHandle<io::Result<()>> {
+    std::thread::spawn(move || emulator_thread(port, plant))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::looks_like_coolbox;
+    use crate::framing::DIAGNOSTIC_REPLY;
+    use crate::telemetry::TelemetryParser;
+
+    fn reply(firmware: &mut Firmware, command: &str) -> String {
+        firmware.handle_command(command.as_bytes())
+    }
+
+    #[test]
+    fn shows_config() {
+        let mut firmware = Firmware::default();
+        assert_eq!(
+            reply(&mut firmware, r#"{"message":"show_config"}"#),
+            "fw_version=1171 \nPID pid_kp=10 pid_kd=5 pid_ki=1 pid_tstep=2 \n\
+             fan_check_time=5 \npwm_frequency=25 \npwm_invert=0 \nmin_pwm_speed=10 \n\
+             max_autofan_pwm=100 \nstart_pwm_speed=70 \nwdt_push_time=5 \nwdt_wait_time=10 \n\
+             thermo_offset1=0 \nthermo_offset2=0 \nled_blink=1 \nOSCCAL diff=-2 OSCCAL=146 \n\
+             fan_number=6 \n\n"
+        );
+    }
+
+    #[test]
+    fn takes_updates_without_a_reply() {
+        let mut firmware = Firmware::default();
+        let update = r#"{"gpu_temp":[55,61],"gpu_mem":[80],"target_temp":65,"fan_mode":1}"#;
+        assert_eq!(reply(&mut firmware, update), "");
+        assert_eq!(firmware.gpu_temp, vec![55, 61]);
+        assert_eq!(firmware.update_settings.target_temp, 65);
+        assert_eq!(firmware.update_settings.fan_mode, 1);
+        assert_eq!(reply(&mut firmware, r#"{"fan_check":1}"#), "");
+    }
+
+    #[test]
+    fn takes_settings_from_messages() {
+        let mut firmware = Firmware::default();
+        assert_eq!(
+            reply(&mut firmware, r#"{"message":"min_pwm_speed=20"}"#),
+            "set min fan speed min_pwm_speed=20 \n\n"
+        );
+        assert_eq!(
+            reply(&mut firmware, r#"{"message":"pid_ki=3"}"#),
+            "set PID coeffs pid_kp=10 pid_kd=5 pid_ki=3 pid_time=2 \n\n"
+        );
+        assert_eq!(
+            reply(&mut firmware, r#"{"message":"led_blink=0"}"#),
+            "turning off LED \n\n"
+        );
+        let settings = &firmware.settings;
+        assert_eq!((settings.min_pwm_speed, settings.pid_ki, settings.led_blink), (20, 3, 0));
+        assert_eq!(
+            reply(&mut firmware, r#"{"message":"default"}"#),
+            "settings was set to default  \n\n"
+        );
+        assert_eq!(firmware.settings, Settings::default());
+    }
+
+    #[test]
+    fn reports_diagnostic() {
+        let mut firmware = Firmware::default();
+        let text = reply(&mut firmware, r#"{"diagnostic":1}"#);
+        assert_eq!(text, DIAGNOSTIC_REPLY);
+        assert!(looks_like_coolbox(&text));
+    }
+
+    #[test]
+    fn ignores_unknown_commands() {
+        let mut firmware = Firmware::default();
+        for command in [
+            r#"{"message":"reboot"}"#,
+            r#"{"message":"target_temp=65"}"#,
+            r#"{"message":"led=on"}"#,
+            r#"{"message":1}"#,
+            "[1,2]",
+        ] {
+            assert_eq!(reply(&mut firmware, command), "", "{}", command);
+        }
+        assert_eq!(firmware.settings, Settings::default());
+        assert_eq!(firmware.update_settings, UpdateSettings::default());
+    }
+
+    #[test]
+    fn prints_telemetry_in_service_mode() {
+        let mut firmware = Firmware::default();
+        assert_eq!(firmware.tick(), "");
+        assert_eq!(
+            reply(&mut firmware, r#"{"message":"service_mode=1"}"#),
+            "service mode ON \n\n"
+        );
+        reply(&mut firmware, r#"{"gpu_temp":[65],"gpu_mem":[80]}"#);
+        let output = firmware.tick();
+        assert!(output.ends_with(" \n\n"));
+        let mut parser = TelemetryParser::default();
+        let telemetry = output.lines().find_map(|line| parser.feed_line(line)).unwrap();
+        assert_eq!((telemetry.fan_pwm, telemetry.pwm_add), (75, 5));
+        assert_eq!((telemetry.max_t, telemetry.max_mem_t), (65, Some(80)));
+        assert_eq!(
+            reply(&mut firmware, r#"{"message":"service_mode=0"}"#),
+            "service mode OFF  \n\n"
+        );
+        assert_eq!(firmware.tick(), "");
+    }
+}
@@ -0,0 +1,258 @@
+//! GPU temperatures reported by an external program, like `nvidia-smi`.
+//!
+//! The command is run through `sh -c` every time the temperatures are needed. Its output can be
+//! - CSV, one GPU per line, the core temperature in the first column and the VRAM one
+//!   in the second, if any. Lines not starting with a number, like the header, are skipped:
+//!   ```text
+//!   temperature.gpu, temperature.memory
+//!   45, 62
+//!   47, N/A
+//!   ```
+//! - JSON, the same as the temperatures sent to `/api/update`:
+//!   ```text
+//!   {"core_temp": [45, 47], "mem_temp": [62, 70]}
+//!   ```
+//!
+//! As with hwmon, the VRAM temperatures are only sent if every GPU has one.
+
+use std::io::{self, Read};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::temperature::{GpuTemperatures, TemperatureSource};
+
+pub const NVIDIA_SMI_COMMAND: &str =
+    "nvidia-smi --query-gpu=temperature.gpu,temperature.memory --format=csv";
+/// How long the command may run before it gets killed.
+pub const DEFAULT_COMMAND_TIMEOUT_MS: u64 = 10_000;
+const COMMAND_POLL_INTERVAL_MS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown output format {:?}, expected csv or json", format)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandSettings {
+    /// The shell command printing the temperatures.
+    pub command: String,
+    pub format: OutputFormat,
+    pub timeout: Duration,
+}
+
+impl Default for CommandSettings {
+    fn default() -> Self {
+        Self {
+            command: NVIDIA_SMI_COMMAND.to_string(),
+            format: OutputFormat::Csv,
+            timeout: Duration::from_millis(DEFAULT_COMMAND_TIMEOUT_MS),
+        }
+    }
+}
+
+/// Reads the GPU temperatures from the output of a command.
+pub struct CommandSource {
+    settings: CommandSettings,
+}
+
+impl CommandSource {
+    pub fn new(settings: CommandSettings) -> Self {
+        Self { settings }
+    }
+
+    /// Runs the command, returning what it has printed to stdout.
+    fn run(&self) -> io::Result<String> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.settings.command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // Read in the background, so a chatty command doesn't block on a full pipe
+        let mut stdout = child.stdout.take().expect("stdout must be piped");
+        let mut stderr = child.stderr.take().expect("stderr must be piped");
+        let stdout_reader = std::thread::spawn(move || {
+            let mut output = String::new();
+            stdout.read_to_string(&mut output).map(|_| output)
+        });
+        let stderr_reader = std::thread::spawn(move || {
+            let mut output = String::new();
+            stderr.read_to_string(&mut output).map(|_| output)
+        });
+        let deadline = Instant::now() + self.settings.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                child.kill().ok();
+                child.wait().ok();
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "{:?} has not finished in {:?}",
+                        self.settings.command, self.settings.timeout
+                    ),
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(COMMAND_POLL_INTERVAL_MS));
+        };
+        let join = |reader: std::thread::JoinHandle<io::Result<String>>| {
+            reader
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("The output reader has panicked")))
+        };
+        let (stdout, stderr) = (join(stdout_reader)?, join(stderr_reader)?);
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "{:?} has failed with {}: {}",
+                self.settings.command,
+                status,
+                stderr.trim()
+            )));
+        }
+        Ok(stdout)
+    }
+}
+
+impl TemperatureSource for CommandSource {
+    fn read(&mut self) -> io::Result<GpuTemperatures> {
+        let output = self.run()?;
+        match self.settings.format {
+            OutputFormat::Csv => Ok(parse_csv(&output)),
+            OutputFormat::Json => parse_json(&output),
+        }
+    }
+}
+
+/// A temperature like "45", "45 C" or "45.6". Anything else, like "N/A", isn't one.
+fn parse_temperature(field: &str) -> Option<i32> {
+    let number = field.split_whitespace().next()?;
+    let temperature = number.parse::<f64>().ok()?;
+    temperature.is_finite().then(|| temperature.round() as i32)
+}
+
+pub fn parse_csv(output: &str) -> GpuTemperatures {
+    let gpus = output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(',');
+            let core = parse_temperature(fields.next()?)?;
+            Some((core, fields.next().and_then(parse_temperature)))
+        })
+        .collect::<Vec<_>>();
+    GpuTemperatures {
+        core: gpus.iter().map(|(core, ..)| *core).collect(),
+        mem: gpus
+            .iter()
+            .map(|(.., mem)| *mem)
+            .collect::<Option<Vec<_>>>()
+            .unwrap_or_default(),
+    }
+}
+
+pub fn parse_json(output: &str) -> io::Result<GpuTemperatures> {
+    #[derive(serde::Deserialize)]
+    struct Temperatures {
+        core_temp: Vec<i32>,
+        #[serde(default)]
+        mem_temp: Vec<i32>,
+    }
+
+    let temperatures = serde_json::from_str::<Temperatures>(output)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mem = if temperatures.mem_temp.len() == temperatures.core_temp.len() {
+        temperatures.mem_temp
+    } else {
+        Vec::new()
+    };
+    Ok(GpuTemperatures {
+        core: temperatures.core_temp,
+        mem,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(command: &str, format: OutputFormat) -> io::Result<GpuTemperatures> {
+        CommandSource::new(CommandSettings {
+            command: command.to_string(),
+            format,
+            timeout: Duration::from_millis(1000),
+        })
+        .read()
+    }
+
+    #[test]
+    fn parses_nvidia_smi_csv() {
+        let output = "temperature.gpu, temperature.memory\n45, 62\n47, 70\n";
+        assert_eq!(
+            parse_csv(output),
+            GpuTemperatures {
+                core: vec![45, 47],
+                mem: vec![62, 70]
+            }
+        );
+        let output = "temperature.gpu, temperature.memory\n45, N/A\n47, 70\n";
+        assert_eq!(
+            parse_csv(output),
+            GpuTemperatures {
+                core: vec![45, 47],
+                mem: vec![]
+            }
+        );
+    }
+
+    #[test]
+    fn parses_json() {
+        assert_eq!(
+            parse_json(r#"{"core_temp": [45, 47], "mem_temp": [62, 70]}"#).unwrap(),
+            GpuTemperatures {
+                core: vec![45, 47],
+                mem: vec![62, 70]
+            }
+        );
+        assert_eq!(parse_json(r#"{"core_temp": [45]}"#).unwrap().mem, Vec::<i32>::new());
+        assert!(parse_json("[45]").is_err());
+    }
+
+    #[test]
+    fn runs_fake_tools() {
+        assert_eq!(
+            read("printf 'temperature.gpu, temperature.memory\\n51, 73\\n'", OutputFormat::Csv)
+                .unwrap(),
+            GpuTemperatures {
+                core: vec![51],
+                mem: vec![73]
+            }
+        );
+        assert_eq!(
+            read(r#"echo '{"core_temp": [60, 61]}'"#, OutputFormat::Json).unwrap(),
+            GpuTemperatures {
+                core: vec![60, 61],
+                mem: vec![]
+            }
+        );
+        assert!(read("echo 'no GPUs' >&2; exit 9", OutputFormat::Csv).is_err());
+        assert_eq!(
+            read("sleep 5", OutputFormat::Csv).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Spinning the fans at a safe speed when the GPU temperatures stop coming.
+//!
+//! Without fresh temperatures the board keeps acting on the last ones it has been given,
+//! which may be long stale if the script sending them has crashed. After a period of silence
+//! the service switches the board into the manual mode with a safe fan speed, and switches
+//! it back to the normal control as soon as the temperatures come again.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::command::{Command, FanMode, Update};
+use crate::telemetry::unix_time_now;
+
+/// How many of the latest fail-safe episodes are kept.
+const MAX_FAILSAFE_EPISODES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct FailsafeSettings {
+    /// How long to wait for the temperatures before engaging the fail-safe, in seconds.
+    pub timeout_s: u64,
+    /// The fan speed while the fail-safe is engaged, in percent.
+    pub speed: u8,
+}
+
+/// A period when the fail-safe has been engaged.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct FailsafeEpisode {
+    /// When the fail-safe has been engaged, in seconds since the Unix epoch.
+    pub engaged_at: u64,
+    /// When the temperatures have come again, in seconds since the Unix epoch.
+    pub resumed_at: Option<u64>,
+}
+
+/// The state of the fail-safe, reported by the health endpoint.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct FailsafeStatus {
+    /// The settings, if the fail-safe is enabled.
+    pub settings: Option<FailsafeSettings>,
+    /// Whether the fans spin at the safe speed right now.
+    pub engaged: bool,
+    /// When the latest temperatures have come, in seconds since the Unix epoch.
+    pub last_temperatures_at: Option<u64>,
+    /// How many times the fail-safe has been engaged.
+    pub engagements: u64,
+    /// The latest episodes, the most recent last.
+    #[cfg_attr(feature = "openapi", schema(value_type = Vec<FailsafeEpisode>))]
+    pub episodes: VecDeque<FailsafeEpisode>,
+}
+
+/// Tracks the arrival of the temperatures and decides when to engage the fail-safe.
+#[derive(Debug, Clone)]
+pub struct Failsafe {
+    status: FailsafeStatus,
+    /// The moment the silence is counted from.
+    last_temperatures: Instant,
+}
+
+impl Default for Failsafe {
+    fn default() -> Self {
+        Self {
+            status: FailsafeStatus::default(),
+            last_temperatures: Instant::now(),
+        }
+    }
+}
+
+impl Failsafe {
+    pub fn status(&self) -> FailsafeStatus {
+        self.status.clone()
+    }
+
+    /// Enables or disables the fail-safe, ending the current episode, if any.
+    /// The silence is counted afresh.
+    pub fn configure(&mut self, settings: Option<FailsafeSettings>) {
+        self.status.settings = settings;
+        self.last_temperatures = Instant::now();
+        self.resume();
+    }
+
+    /// Notes that fresh temperatures have come, ending the fail-safe episode, if any.
+    pub fn temperatures_arrived(&mut self) {
+        self.last_temperatures = Instant::now();
+        self.status.last_temperatures_at = Some(unix_time_now());
+        if self.resume() {
+            log::info!("GPU temperatures are coming again, the fail-safe is off");
+        }
+    }
+
+    fn resume(&mut self) -> bool {
+        if !self.status.engaged {
+            return false;
+        }
+        self.status.engaged = false;
+        if let Some(episode) = self.status.episodes.back_mut() {
+            episode.resumed_at = Some(unix_time_now());
+        }
+        true
+    }
+
+    /// Engages the fail-safe if the temperatures have not come for too long.
+    /// Returns whether it has been engaged just now.
+    pub fn check(&mut self) -> bool {
+        let Some(settings) = self.status.settings else {
+            return false;
+        };
+        let silence = self.last_temperatures.elapsed();
+        if self.status.engaged || silence < Duration::from_secs(settings.timeout_s) {
+            return false;
+        }
+        log::warn!(
+            "No GPU temperatures for {:?}, spinning the fans at {}%",
+            silence,
+            settings.speed
+        );
+        self.status.engaged = true;
+        self.status.engagements += 1;
+        if self.status.episodes.len() == MAX_FAILSAFE_EPISODES {
+            self.status.episodes.pop_front();
+        }
+        self.status.episodes.push_back(FailsafeEpisode {
+            engaged_at: unix_time_now(),
+            resumed_at: None,
+        });
+        true
+    }
+
+    /// The fan speed to use instead of the normal control, while the fail-safe is engaged.
+    pub fn speed(&self) -> Option<u8> {
+        let settings = self.status.settings?;
+        self.status.engaged.then_some(settings.speed)
+    }
+}
+
+/// Parses the fail-safe timeout in seconds. A zero timeout would engage the fail-safe
+/// between any two updates, so it's refused.
+pub fn parse_timeout(timeout_s: &str) -> Result<u64, String> {
+    match timeout_s.trim().parse::<u64>() {
+        Ok(0) => Err("The fail-safe timeout must be at least 1 second".to_string()),
+        Ok(timeout_s) => Ok(timeout_s),
+        Err(e) => Err(format!("Invalid fail-safe timeout {:?}: {}", timeout_s, e)),
+    }
+}
+
+/// The command switching the board into the manual mode with the safe speed,
+/// keeping the rest of the settings of the desired state.
+pub fn failsafe_command(desired_state: Option<&Command>, speed: u8) -> Command {
+    let settings = match desired_state {
+        Some(Command::Update(update)) => update.settings(),
+        _ => Update::default(),
+    };
+    Update {
+        fan_mode: Some(FanMode::Manual),
+        manual_fan_speed: Some(speed),
+        ..settings
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timeout() {
+        assert_eq!(parse_timeout("60"), Ok(60));
+        assert_eq!(parse_timeout("1"), Ok(1));
+        assert!(parse_timeout("0").is_err());
+        assert!(parse_timeout("-5").is_err());
+        assert!(parse_timeout("1m").is_err());
+    }
+
+    #[test]
+    fn engages_after_silence_and_resumes() {
+        let mut failsafe = Failsafe::default();
+        assert!(!failsafe.check());
+        failsafe.configure(Some(FailsafeSettings {
+            timeout_s: 0,
+            speed: 100,
+        }));
+        assert!(failsafe.check());
+        assert!(!failsafe.check(), "Engaged once per episode");
+        assert_eq!(failsafe.speed(), Some(100));
+        failsafe.temperatures_arrived();
+        assert_eq!(failsafe.speed(), None);
+        let status = failsafe.status();
+        assert_eq!(status.engagements, 1);
+        assert!(status.episodes[0].resumed_at.is_some());
+    }
+
+    #[test]
+    fn keeps_desired_settings() {
+        let desired = Update::for_temperatures(vec![60], vec![80], 70, 90, Some(5), None);
+        assert_eq!(
+            failsafe_command(Some(&desired.into()), 100),
+            Command::Update(Update {
+                target_temp: Some(70),
+                target_mem: Some(90),
+                fan_mode: Some(FanMode::Manual),
+                manual_fan_speed: Some(100),
+                watchdog: Some(true),
+                wd_reset_interval: Some(5),
+                ..Default::default()
+            })
+        );
+    }
+}
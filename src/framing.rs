@@ -0,0 +1,322 @@
+use std::fmt;
+use std::io::{self};
+use std::time::Duration;
+
+/// The usual deadline for the board to reply.
+pub const DEFAULT_REPLY_TIMEOUT_MS: u64 = 2000;
+/// The firmware doesn't acknowledge updates and fan checks: they are done
+/// once the board has been quiet for this long after them.
+pub const QUIET_PERIOD_MS: u64 = 300;
+/// The longest a caller may ask to wait for a reply.
+pub const MAX_REPLY_TIMEOUT_MS: u64 = 60_000;
+
+/// The reply of firmware 1271 to `{"diagnostic":1}`.
+#[cfg(test)]
+pub(crate) const DIAGNOSTIC_REPLY: &str = concat!(
+    r#"{"mcu_version":"m328p","pcb_version":1031,"fw_version":1271,"#,
+    r#""msg_errors":0,"reboot_errors":0}"#,
+    "\n"
+);
+
+/// Cuts JSON objects out of a byte stream.
+#[derive(Default)]
+pub struct JsonObjectFramer {
+    buffer: Vec<u8>,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonObjectFramer {
+    /// Consumes the next byte. Returns an object once its closing brace arrives.
+    /// Anything outside the objects is skipped.
+    pub fn feed_byte(&mut self, byte: u8) -> Option<Vec<u8>> {
+        if self.depth == 0 && byte != b'{' {
+            return None;
+        }
+        self.buffer.push(byte);
+        if self.in_string {
+            match byte {
+                _ if self.escaped => self.escaped = false,
+                b'\\' => self.escaped = true,
+                b'"' => self.in_string = false,
+                _ => {}
+            }
+            return None;
+        }
+        match byte {
+            b'"' => self.in_string = true,
+            b'{' => self.depth += 1,
+            b'}' => {
+                self.depth -= 1;
+                if self.depth == 0 {
+                    return Some(std::mem::take(&mut self.buffer));
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+/// A way a reply of the firmware can end.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplyEnd {
+    /// The reply is a JSON object, complete once its braces are balanced.
+    JsonObject,
+    /// The reply is text ending with the given trailer,
+    /// for instance an empty line (`"\n\n"`) the firmware puts after every text reply.
+    Trailer(&'static str),
+    /// The command isn't acknowledged, so the reply is whatever comes before the board
+    /// has been quiet for the given time, most likely nothing.
+    Silence(Duration),
+}
+
+/// What the reply to a command looks like and how long to wait for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplySpec {
+    /// The reply is complete as soon as it matches any of these.
+    pub ends: Vec<ReplyEnd>,
+    pub timeout: Duration,
+}
+
+impl ReplySpec {
+    fn new(ends: Vec<ReplyEnd>) -> Self {
+        Self {
+            ends,
+            timeout: Duration::from_millis(DEFAULT_REPLY_TIMEOUT_MS),
+        }
+    }
+
+    /// A text reply followed by an empty line, like `service mode ON \n\n`.
+    pub fn text() -> Self {
+        Self::new(vec![ReplyEnd::Trailer("\n\n")])
+    }
+
+    /// A JSON object, like the reply to `{"diagnostic":1}`.
+    pub fn json() -> Self {
+        Self::new(vec![ReplyEnd::JsonObject])
+    }
+
+    /// No reply at all.
+    pub fn unacknowledged() -> Self {
+        Self::new(vec![ReplyEnd::Silence(Duration::from_millis(QUIET_PERIOD_MS))])
+    }
+
+    /// How long the board has to stay quiet for the reply to be complete, if silence ends it.
+    pub fn quiet_period(&self) -> Option<Duration> {
+        self.ends.iter().find_map(|end| match end {
+            ReplyEnd::Silence(quiet_period) => Some(*quiet_period),
+            _ => None,
+        })
+    }
+
+    /// Starts collecting a reply.
+    pub fn framer(&self) -> ReplyFramer<'_> {
+        ReplyFramer {
+            spec: self,
+            reply: Vec::new(),
+            json: JsonObjectFramer::default(),
+            not_json: false,
+        }
+    }
+
+    /// Turns a complete reply into text, making sure it's not garbled.
+    pub fn decode(&self, reply: Vec<u8>) -> Result<String, CommandError> {
+        let text = match String::from_utf8(reply) {
+            Ok(text) => text,
+            Err(e) => return Err(CommandError::Garbled(e.into_bytes())),
+        };
+        let looks_like_json = text.trim_start().starts_with('{');
+        if looks_like_json
+            && self.ends.contains(&ReplyEnd::JsonObject)
+            && serde_json::from_str::<serde_json::Value>(text.trim()).is_err()
+        {
+            return Err(CommandError::Garbled(text.into_bytes()));
+        }
+        Ok(text)
+    }
+}
+
+/// Collects a reply byte by byte, telling when it's complete.
+/// Every byte is looked at once, however long the reply gets.
+pub struct ReplyFramer<'a> {
+    spec: &'a ReplySpec,
+    reply: Vec<u8>,
+    json: JsonObjectFramer,
+    /// The reply has started with something else than an object.
+    not_json: bool,
+}
+
+impl ReplyFramer<'_> {
+    /// Consumes the next byte. Returns `true` once the reply is complete.
+    pub fn feed_byte(&mut self, byte: u8) -> bool {
+        self.reply.push(byte);
+        // The reply must be the object itself, not some text with braces inside
+        if self.json.depth == 0 && byte != b'{' && !byte.is_ascii_whitespace() {
+            self.not_json = true;
+        }
+        let object_complete = !self.not_json && self.json.feed_byte(byte).is_some();
+        self.spec.ends.iter().any(|end| match end {
+            ReplyEnd::JsonObject => object_complete,
+            ReplyEnd::Trailer(trailer) => self.reply.ends_with(trailer.as_bytes()),
+            ReplyEnd::Silence(..) => false,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reply.is_empty()
+    }
+
+    pub fn into_reply(self) -> Vec<u8> {
+        self.reply
+    }
+}
+
+/// Why a command has failed.
+#[derive(Debug)]
+pub enum CommandError {
+    /// Unable to talk to the device.
+    Io(io::Error),
+    /// The device hasn't replied at all.
+    Timeout,
+    /// The device has started replying, but hasn't finished in time.
+    Partial(String),
+    /// The reply is complete, but makes no sense.
+    Garbled(Vec<u8>),
+    /// Too many commands are waiting for the device already.
+    QueueFull,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Unable to talk to the device: {}", e),
+            Self::Timeout => write!(f, "The device has not replied in time"),
+            Self::Partial(reply) => write!(f, "The device's reply is incomplete: {:?}", reply),
+            Self::Garbled(reply) => write!(
+                f,
+                "The device's reply is garbled: {:?}",
+                String::from_utf8_lossy(reply)
+            ),
+            Self::QueueFull => write!(f, "The device is busy, too many commands are waiting"),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<io::Error> for CommandError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<CommandError> for io::Error {
+    fn from(e: CommandError) -> Self {
+        match e {
+            CommandError::Io(e) => e,
+            CommandError::Timeout => io::Error::new(io::ErrorKind::TimedOut, e.to_string()),
+            CommandError::Partial(..) => io::Error::new(io::ErrorKind::UnexpectedEof, e.to_string()),
+            CommandError::Garbled(..) => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+            CommandError::QueueFull => io::Error::new(io::ErrorKind::ResourceBusy, e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(stream: &[u8]) -> Vec<String> {
+        let mut framer = JsonObjectFramer::default();
+        stream
+            .iter()
+            .filter_map(|&byte| framer.feed_byte(byte))
+            .map(|object| String::from_utf8(object).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn frames_objects_with_braces_in_strings() {
+        assert_eq!(
+            frame(br#"noise {"message":"a } b {"} more {"a":{"b":1}}"#),
+            vec![r#"{"message":"a } b {"}"#, r#"{"a":{"b":1}}"#]
+        );
+    }
+
+    #[test]
+    fn frames_objects_with_escaped_quotes() {
+        assert_eq!(
+            frame(br#"{"message":"say \"}\" \\"}{"x":"\\\"{"}"#),
+            vec![r#"{"message":"say \"}\" \\"}"#, r#"{"x":"\\\"{"}"#]
+        );
+    }
+
+    /// Whether the reply is complete once all of it has come, and not a byte earlier.
+    fn completes(spec: &ReplySpec, reply: &[u8]) -> bool {
+        let mut framer = spec.framer();
+        let complete_at = reply.iter().position(|&byte| framer.feed_byte(byte));
+        complete_at.is_some_and(|position| position == reply.len() - 1)
+    }
+
+    #[test]
+    fn completes_text_replies() {
+        let text = ReplySpec::text();
+        assert!(completes(&text, b"service mode ON \n\n"));
+        assert!(!completes(&text, b"service mode ON \n"));
+        assert!(completes(&text, b"fw_version=1171 \nfan_check_time=5 \nfan_number=6 \n\n"));
+        assert!(!completes(&text, DIAGNOSTIC_REPLY.trim_end().as_bytes()));
+    }
+
+    #[test]
+    fn completes_json_replies() {
+        let json = ReplySpec::json();
+        assert!(completes(&json, DIAGNOSTIC_REPLY.trim_end().as_bytes()));
+        assert!(completes(&json, b"\r\n{\"fw_version\":1271}"));
+        assert!(!completes(&json, b"{\"mcu_version\":\"}"));
+        // Text before the object isn't a JSON reply
+        assert!(!completes(&json, b"value {\"a\":1}"));
+        assert!(!completes(&json, b"service mode ON \n\n"));
+    }
+
+    #[test]
+    fn waits_for_silence_after_unacknowledged_commands() {
+        let unacknowledged = ReplySpec::unacknowledged();
+        assert_eq!(
+            unacknowledged.quiet_period(),
+            Some(Duration::from_millis(QUIET_PERIOD_MS))
+        );
+        assert!(!completes(&unacknowledged, b"service mode ON \n\n"));
+        assert!(!completes(&unacknowledged, DIAGNOSTIC_REPLY.trim_end().as_bytes()));
+        assert_eq!(ReplySpec::text().quiet_period(), None);
+        assert_eq!(ReplySpec::unacknowledged().decode(Vec::new()).unwrap(), "");
+    }
+
+    #[test]
+    fn decodes_replies() {
+        assert_eq!(
+            ReplySpec::text().decode(b"service mode ON \n\n".to_vec()).unwrap(),
+            "service mode ON \n\n"
+        );
+        let json = ReplySpec::json();
+        assert_eq!(json.decode(DIAGNOSTIC_REPLY.into()).unwrap(), DIAGNOSTIC_REPLY);
+        assert!(matches!(
+            json.decode(b"{\"a\":1,}".to_vec()),
+            Err(CommandError::Garbled(reply)) if reply == b"{\"a\":1,}"
+        ));
+        assert!(matches!(
+            ReplySpec::text().decode(vec![b'o', 0xff, b'k']),
+            Err(CommandError::Garbled(reply)) if reply == [b'o', 0xff, b'k']
+        ));
+    }
+
+    #[test]
+    fn classifies_errors() {
+        let kind = |e: CommandError| io::Error::from(e).kind();
+        assert_eq!(kind(CommandError::Timeout), io::ErrorKind::TimedOut);
+        assert_eq!(kind(CommandError::Partial("sett".to_string())), io::ErrorKind::UnexpectedEof);
+        assert_eq!(kind(CommandError::Garbled(b"{".to_vec())), io::ErrorKind::InvalidData);
+        assert_eq!(kind(CommandError::QueueFull), io::ErrorKind::ResourceBusy);
+    }
+}
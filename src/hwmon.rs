@@ -0,0 +1,249 @@
+//! GPU temperatures from the Linux hwmon interface.
+//!
+//! Every hwmon device is a directory like `/sys/class/hwmon/hwmon3`, with the driver's name
+//! in `name`, and the sensors in `temp<N>_input` (millidegrees) and `temp<N>_label`:
+//! ```text
+//! name         amdgpu
+//! temp1_input  45000   temp1_label  edge
+//! temp2_input  48000   temp2_label  junction
+//! temp3_input  62000   temp3_label  mem
+//! ```
+//! A device is taken for a GPU if it has a sensor labelled as a core one. The GPUs are ordered
+//! by their PCI addresses, so the order stays the same across reboots.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::temperature::{GpuTemperatures, TemperatureSource};
+
+pub const DEFAULT_HWMON_ROOT: &str = "/sys/class/hwmon";
+/// The driver reporting unlabelled sensors in the well known order.
+const AMDGPU_DRIVER: &str = "amdgpu";
+const AMDGPU_CORE_SENSOR: u32 = 1;
+const AMDGPU_MEM_SENSOR: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HwmonSettings {
+    /// The directory with the hwmon devices.
+    pub root: PathBuf,
+    /// Labels of the sensors measuring the GPU core, the first one found is used.
+    pub core_labels: Vec<String>,
+    /// Labels of the sensors measuring the GPU VRAM, the first one found is used.
+    pub mem_labels: Vec<String>,
+}
+
+impl Default for HwmonSettings {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from(DEFAULT_HWMON_ROOT),
+            core_labels: vec!["edge".to_string()],
+            mem_labels: vec!["mem".to_string()],
+        }
+    }
+}
+
+struct Sensor {
+    index: u32,
+    label: Option<String>,
+    millidegrees: i64,
+}
+
+/// Reads the GPU temperatures from hwmon.
+pub struct HwmonSource {
+    settings: HwmonSettings,
+}
+
+impl HwmonSource {
+    pub fn new(settings: HwmonSettings) -> Self {
+        Self { settings }
+    }
+
+    /// The core and VRAM temperatures of the device, if it's a GPU.
+    fn read_device(&self, device_path: &Path) -> Option<(i32, Option<i32>)> {
+        let driver = read_trimmed(&device_path.join("name")).unwrap_or_default();
+        let sensors = read_sensors(device_path);
+        let find = |labels: &[String], amdgpu_sensor: u32| {
+            let labelled = labels.iter().find_map(|wanted| {
+                sensors.iter().find(|sensor| {
+                    sensor
+                        .label
+                        .as_deref()
+                        .is_some_and(|label| label.eq_ignore_ascii_case(wanted))
+                })
+            });
+            let unlabelled = || {
+                sensors.iter().find(|sensor| {
+                    driver == AMDGPU_DRIVER
+                        && sensor.label.is_none()
+                        && sensor.index == amdgpu_sensor
+                })
+            };
+            labelled
+                .or_else(unlabelled)
+                .map(|sensor| (sensor.millidegrees as f64 / 1000.0).round() as i32)
+        };
+        let core = find(&self.settings.core_labels, AMDGPU_CORE_SENSOR)?;
+        Some((core, find(&self.settings.mem_labels, AMDGPU_MEM_SENSOR)))
+    }
+}
+
+impl TemperatureSource for HwmonSource {
+    fn read(&mut self) -> io::Result<GpuTemperatures> {
+        let mut devices = fs::read_dir(&self.settings.root)?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .map(|path| {
+                let sort_key = fs::canonicalize(path.join("device")).unwrap_or(path.clone());
+                (sort_key, path)
+            })
+            .collect::<Vec<_>>();
+        devices.sort();
+        let gpus = devices
+            .iter()
+            .filter_map(|(.., path)| self.read_device(path))
+            .collect::<Vec<_>>();
+        let core = gpus.iter().map(|(core, ..)| *core).collect();
+        // The board takes either no VRAM temperatures, or one per GPU
+        let mem = gpus
+            .iter()
+            .map(|(.., mem)| *mem)
+            .collect::<Option<Vec<_>>>()
+            .unwrap_or_default();
+        Ok(GpuTemperatures { core, mem })
+    }
+}
+
+fn read_trimmed(path: &Path) -> io::Result<String> {
+    Ok(fs::read_to_string(path)?.trim().to_string())
+}
+
+/// The temperature sensors of the device which can be read at the moment.
+fn read_sensors(device_path: &Path) -> Vec<Sensor> {
+    let Ok(entries) = fs::read_dir(device_path) else {
+        return Vec::new();
+    };
+    let mut sensors = entries
+        .filter_map(|entry| {
+            let file_name = entry.ok()?.file_name();
+            let index = file_name
+                .to_str()?
+                .strip_prefix("temp")?
+                .strip_suffix("_input")?
+                .parse::<u32>()
+                .ok()?;
+            let millidegrees = read_trimmed(&device_path.join(&file_name)).ok()?.parse().ok()?;
+            let label = read_trimmed(&device_path.join(format!("temp{}_label", index))).ok();
+            Some(Sensor {
+                index,
+                label,
+                millidegrees,
+            })
+        })
+        .collect::<Vec<_>>();
+    sensors.sort_by_key(|sensor| sensor.index);
+    sensors
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A sensor of a fake device: its file name prefix, label and value.
+    type FakeSensor<'a> = (&'a str, Option<&'a str>, &'a str);
+
+    /// A fake `/sys/class/hwmon`, removed once dropped.
+    fn fake_hwmon(devices: &[(&str, &[FakeSensor])]) -> TempDir {
+        let root = TempDir::new().unwrap();
+        for (i, (driver, sensors)) in devices.iter().enumerate() {
+            let device_path = root.path().join(format!("hwmon{}", i));
+            fs::create_dir_all(&device_path).unwrap();
+            fs::write(device_path.join("name"), format!("{}\n", driver)).unwrap();
+            for (sensor, label, value) in sensors.iter() {
+                fs::write(device_path.join(format!("{}_input", sensor)), value).unwrap();
+                if let Some(label) = label {
+                    fs::write(device_path.join(format!("{}_label", sensor)), label).unwrap();
+                }
+            }
+        }
+        root
+    }
+
+    fn read(root: &TempDir, settings: HwmonSettings) -> GpuTemperatures {
+        HwmonSource::new(HwmonSettings {
+            root: root.path().to_path_buf(),
+            ..settings
+        })
+        .read()
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_labelled_gpus_only() {
+        let root = fake_hwmon(&[
+            (
+                "amdgpu",
+                &[
+                    ("temp1", Some("edge"), "45000"),
+                    ("temp3", Some("mem"), "61600"),
+                ],
+            ),
+            ("k10temp", &[("temp1", Some("Tctl"), "50000")]),
+            (
+                "amdgpu",
+                &[
+                    ("temp1", Some("edge\n"), "47499"),
+                    ("temp3", Some("mem"), "70000"),
+                ],
+            ),
+        ]);
+        assert_eq!(
+            read(&root, HwmonSettings::default()),
+            GpuTemperatures {
+                core: vec![45, 47],
+                mem: vec![62, 70]
+            }
+        );
+    }
+
+    #[test]
+    fn falls_back_to_amdgpu_sensor_order() {
+        let root = fake_hwmon(&[(
+            "amdgpu",
+            &[("temp1", None, "40000"), ("temp3", None, "55000")],
+        )]);
+        assert_eq!(
+            read(&root, HwmonSettings::default()),
+            GpuTemperatures {
+                core: vec![40],
+                mem: vec![55]
+            }
+        );
+    }
+
+    #[test]
+    fn uses_configured_labels_and_skips_partial_mem() {
+        let root = fake_hwmon(&[
+            ("nouveau", &[("temp1", Some("GPU core"), "60000")]),
+            (
+                "amdgpu",
+                &[
+                    ("temp2", Some("junction"), "65000"),
+                    ("temp3", Some("mem"), "80000"),
+                ],
+            ),
+        ]);
+        let settings = HwmonSettings {
+            core_labels: vec!["junction".to_string(), "GPU core".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            read(&root, settings),
+            GpuTemperatures {
+                core: vec![60, 65],
+                mem: vec![]
+            }
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt;
+use std::io::{self};
+use std::path::{Path, PathBuf};
+
+use serialport::{SerialPortInfo, SerialPortType};
+
+pub const SERIAL_BY_PATH_DIR: &str = "/dev/serial/by-path";
+pub const SERIAL_BY_ID_DIR: &str = "/dev/serial/by-id";
+
+/// Everything that tells one board from another, regardless of the name
+/// the kernel has given to its tty device this time.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct BoardIdentity {
+    /// Path of the tty device.
+    #[cfg_attr(feature = "openapi", schema(example = "/dev/ttyUSB0"))]
+    pub port: String,
+    /// USB vendor id of the USB-serial adapter.
+    #[cfg_attr(feature = "openapi", schema(example = 6790))]
+    pub vid: Option<u16>,
+    /// USB product id of the USB-serial adapter.
+    #[cfg_attr(feature = "openapi", schema(example = 29987))]
+    pub pid: Option<u16>,
+    /// Serial number of the USB-serial adapter. Cheap adapters often don't have any.
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    /// The link in `/dev/serial/by-path` pointing to the device, stable for as long
+    /// as the board is plugged into the same USB port.
+    #[cfg_attr(feature = "openapi", schema(example = "/dev/serial/by-path/pci-0000:00:14.0-usb-0:2:1.0-port0"))]
+    pub by_path: Option<String>,
+    /// The link in `/dev/serial/by-id` pointing to the device.
+    pub by_id: Option<String>,
+}
+
+impl BoardIdentity {
+    pub fn from_port_info(info: &SerialPortInfo) -> Self {
+        let mut identity = Self {
+            port: info.port_name.clone(),
+            by_path: find_link_to(SERIAL_BY_PATH_DIR, &info.port_name),
+            by_id: find_link_to(SERIAL_BY_ID_DIR, &info.port_name),
+            ..Default::default()
+        };
+        if let SerialPortType::UsbPort(usb) = &info.port_type {
+            identity.vid = Some(usb.vid);
+            identity.pid = Some(usb.pid);
+            identity.serial_number = usb.serial_number.clone();
+            identity.manufacturer = usb.manufacturer.clone();
+            identity.product = usb.product.clone();
+        }
+        identity
+    }
+
+    /// Collects the identity of the device behind the given tty.
+    /// Only the path is known if the port is not listed among the system's serial ports.
+    pub fn of_port(port: &str) -> Self {
+        let ports = serialport::available_ports().unwrap_or_default();
+        match ports.iter().find(|info| same_file(&info.port_name, port)) {
+            Some(info) => Self {
+                port: port.to_string(),
+                ..Self::from_port_info(info)
+            },
+            None => Self {
+                port: port.to_string(),
+                by_path: find_link_to(SERIAL_BY_PATH_DIR, port),
+                by_id: find_link_to(SERIAL_BY_ID_DIR, port),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+fn same_file(a: impl AsRef<Path>, b: impl AsRef<Path>) -> bool {
+    match (a.as_ref().canonicalize(), b.as_ref().canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a.as_ref() == b.as_ref(),
+    }
+}
+
+/// Finds a symlink in the directory pointing to the given device.
+fn find_link_to(links_dir: &str, port: &str) -> Option<String> {
+    let target = Path::new(port).canonicalize().ok()?;
+    std::fs::read_dir(links_dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|link| link.canonicalize().is_ok_and(|resolved| resolved == target))
+        .map(|link| link.to_string_lossy().to_string())
+}
+
+/// Tells how to find the port of a board: either directly by its path,
+/// or by the identity of the board's USB-serial adapter. Written as
+/// - `/dev/ttyUSB0` - a path of the tty device;
+/// - `serial:A50285BI` - a serial number of the USB adapter;
+/// - `usb:1a86:7523` - USB vendor and product ids (hexadecimal), fine if only one such adapter is attached;
+/// - `by-path:pci-0000:00:14.0-usb-0:2:1.0-port0` - a link in `/dev/serial/by-path`;
+/// - `auto` - all boards that can be found.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PortSelector {
+    Path(String),
+    SerialNumber(String),
+    UsbId { vid: u16, pid: u16 },
+    ByPath(String),
+    Auto,
+}
+
+impl std::str::FromStr for PortSelector {
+    type Err = String;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        if selector == "auto" {
+            return Ok(Self::Auto);
+        }
+        if selector.starts_with('/') {
+            return Ok(Self::Path(selector.to_string()));
+        }
+        match selector.split_once(':') {
+            Some(("serial", serial)) if !serial.is_empty() => {
+                Ok(Self::SerialNumber(serial.to_string()))
+            }
+            Some(("by-path", by_path)) if !by_path.is_empty() => {
+                Ok(Self::ByPath(by_path.to_string()))
+            }
+            Some(("usb", ids)) => {
+                let parse_id = |id: &str| u16::from_str_radix(id, 16).ok();
+                match ids.split_once(':') {
+                    Some((vid, pid)) => match (parse_id(vid), parse_id(pid)) {
+                        (Some(vid), Some(pid)) => Ok(Self::UsbId { vid, pid }),
+                        _ => Err(format!("Invalid USB ids {:?}, expected like usb:1a86:7523", ids)),
+                    },
+                    None => Err(format!("Invalid USB ids {:?}, expected like usb:1a86:7523", ids)),
+                }
+            }
+            // Something like "ttyUSB0"
+            _ if !selector.is_empty() && !selector.contains(':') => {
+                Ok(Self::Path(selector.to_string()))
+            }
+            _ => Err(format!("Invalid port {:?}", selector)),
+        }
+    }
+}
+
+impl fmt::Display for PortSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Path(path) => write!(f, "{}", path),
+            Self::SerialNumber(serial) => write!(f, "serial:{}", serial),
+            Self::UsbId { vid, pid } => write!(f, "usb:{:04x}:{:04x}", vid, pid),
+            Self::ByPath(by_path) => write!(f, "by-path:{}", by_path),
+            Self::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+impl PortSelector {
+    /// A board id used when none is given explicitly.
+    pub fn default_board_id(&self) -> String {
+        let id = match self {
+            Self::Path(path) => Path::new(path)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.clone()),
+            other => other.to_string(),
+        };
+        id.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                    c
+                } else {
+                    '-'
+                }
+            })
+            .collect()
+    }
+
+    /// Finds the current path of the tty device the selector points to.
+    pub fn resolve(&self) -> io::Result<String> {
+        let not_found = || {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No serial port matches {}", self),
+            )
+        };
+        match self {
+            Self::Path(path) => Ok(path.clone()),
+            Self::ByPath(by_path) => {
+                let link = if by_path.starts_with('/') {
+                    PathBuf::from(by_path)
+                } else {
+                    Path::new(SERIAL_BY_PATH_DIR).join(by_path)
+                };
+                let resolved = link.canonicalize().map_err(|_| not_found())?;
+                Ok(resolved.to_string_lossy().to_string())
+            }
+            Self::SerialNumber(..) | Self::UsbId { .. } => {
+                let ports = serialport::available_ports().map_err(io::Error::other)?;
+                let mut matching = ports.iter().filter(|info| self.matches(info));
+                let found = matching.next().ok_or_else(not_found)?;
+                if matching.next().is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("More than one serial port matches {}", self),
+                    ));
+                }
+                Ok(found.port_name.clone())
+            }
+            Self::Auto => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "\"auto\" must be resolved through discovery",
+            )),
+        }
+    }
+
+    fn matches(&self, info: &SerialPortInfo) -> bool {
+        let SerialPortType::UsbPort(usb) = &info.port_type else {
+            return false;
+        };
+        match self {
+            Self::SerialNumber(serial) => usb.serial_number.as_deref() == Some(serial.as_str()),
+            Self::UsbId { vid, pid } => usb.vid == *vid && usb.pid == *pid,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_selectors() {
+        let parse = |selector: &str| selector.parse::<PortSelector>();
+        assert_eq!(parse("/dev/ttyUSB0"), Ok(PortSelector::Path("/dev/ttyUSB0".to_string())));
+        assert_eq!(parse("ttyUSB1"), Ok(PortSelector::Path("ttyUSB1".to_string())));
+        assert_eq!(
+            parse("serial:A50285BI"),
+            Ok(PortSelector::SerialNumber("A50285BI".to_string()))
+        );
+        assert_eq!(
+            parse("usb:1a86:7523"),
+            Ok(PortSelector::UsbId {
+                vid: 0x1a86,
+                pid: 0x7523
+            })
+        );
+        assert_eq!(
+            parse("by-path:pci-0000:00:14.0-usb-0:2:1.0-port0"),
+            Ok(PortSelector::ByPath("pci-0000:00:14.0-usb-0:2:1.0-port0".to_string()))
+        );
+        assert_eq!(parse("auto"), Ok(PortSelector::Auto));
+    }
+
+    #[test]
+    fn rejects_malformed_selectors() {
+        for selector in [
+            "usb:1a86",
+            "usb:1a86:75zz",
+            "usb:1a86:17523",
+            "usb:",
+            "serial:",
+            "by-path:",
+            "tcp:localhost:4000",
+            "",
+        ] {
+            assert!(selector.parse::<PortSelector>().is_err(), "{:?}", selector);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_display() {
+        for selector in [
+            "/dev/ttyUSB0",
+            "serial:A50285BI",
+            "usb:1a86:7523",
+            "by-path:pci-0",
+            "auto",
+        ] {
+            assert_eq!(selector.parse::<PortSelector>().unwrap().to_string(), selector);
+        }
+    }
+
+    #[test]
+    fn makes_default_board_ids() {
+        let id = |selector: &str| selector.parse::<PortSelector>().unwrap().default_board_id();
+        assert_eq!(id("/dev/ttyUSB0"), "ttyUSB0");
+        assert_eq!(id("serial:A50285BI"), "serial-A50285BI");
+        assert_eq!(id("usb:1a86:7523"), "usb-1a86-7523");
+    }
+}
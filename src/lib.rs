@@ -0,0 +1,49 @@
+//! Driver of the Coolbox Autofan Pro board, and the REST API service built on it.
+//!
+//! Without the default features only the serial driver is built, which is enough to control
+//! the board from another program:
+//! ```no_run
+//! use coolbox_rs::autofan::Priority;
+//! use coolbox_rs::{Command, CoolboxAutofan};
+//!
+//! let autofan = CoolboxAutofan::try_from("/dev/ttyUSB0".to_string())?;
+//! let reply = futures::executor::block_on(autofan.send_command(
+//!     &Command::Diagnostic,
+//!     Priority::Normal,
+//!     None,
+//! ))?;
+//! println!("{}", reply);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Features:
+//! - `openapi` - OpenAPI schemas of the public types;
+//! - `server` - the REST API ([`api`]);
+//! - `swagger-ui` - the API docs served by the service.
+
+#[cfg(feature = "server")]
+pub mod api;
+pub mod autofan;
+pub mod capture;
+pub mod command;
+pub mod config;
+pub mod control;
+pub mod discovery;
+pub mod emulator;
+pub mod external;
+pub mod failsafe;
+pub mod framing;
+pub mod hwmon;
+pub mod identity;
+pub mod metrics;
+pub mod registry;
+pub mod shutdown;
+pub mod simulation;
+pub mod state;
+pub mod telemetry;
+pub mod temperature;
+
+pub use autofan::{CoolboxAutofan, open_coolbox_autofan_port};
+pub use command::{Command, FanMode, Update};
+pub use framing::CommandError;
+pub use telemetry::{OutputRouter, Telemetry, TelemetryParser};
@@ -1,4 +1,6 @@
 use std::io::{self};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use actix_web::{
     App, HttpServer,
@@ -8,18 +10,41 @@ use actix_web::{
 use argh::FromArgs;
 use utoipa::OpenApi;
 use utoipa_actix_web::AppExt;
+#[cfg(feature = "swagger-ui")]
 use utoipa_swagger_ui::SwaggerUi;
 
-mod api;
-mod autofan;
-use autofan::CoolboxAutofan;
+mod client;
+use client::{Backend, ClientCommand, OutputFormat};
+use coolbox_rs::api;
+use coolbox_rs::autofan::CoolboxAutofan;
+use coolbox_rs::capture::CaptureRecorder;
+use coolbox_rs::control::{FanCurve, PidSettings};
+use coolbox_rs::discovery;
+use coolbox_rs::failsafe::{self, FailsafeSettings};
+use coolbox_rs::external::{self, CommandSettings, CommandSource};
+use coolbox_rs::hwmon::{self, HwmonSettings, HwmonSource};
+use coolbox_rs::identity::PortSelector;
+use coolbox_rs::registry::{BoardRegistry, BoardSpec};
+use coolbox_rs::shutdown::ShutdownAction;
+use coolbox_rs::simulation::SimulationSettings;
+use coolbox_rs::state::StateFile;
+use coolbox_rs::temperature::{TemperatureCollector, TemperatureSource};
+
+/// How long the stopping service waits for the requests in progress, in seconds.
+const SHUTDOWN_TIMEOUT_S: u64 = 5;
 
 /// Coolbox Autofan Pro controller with REST API. Tested on firmware 1271 and PCB 1031.
+/// Without a command, serves the REST API. With a command, acts as a client of the running service.
 #[derive(FromArgs, Debug)]
 struct WebCli {
-    /// serial port of the Coolbox Autofan board. Default: "/dev/ttyUSB0"
-    #[argh(option, short = 'c', default = "\"/dev/ttyUSB0\".to_string()")]
-    coolbox_port: String,
+    /// serial port of a Coolbox Autofan board, optionally prefixed with the board's id,
+    /// like "rig1=/dev/ttyUSB0". Can be repeated to serve multiple boards.
+    /// Instead of a path, the port can be found by the USB adapter's serial number
+    /// ("serial:A50285BI"), USB ids ("usb:1a86:7523") or a link in /dev/serial/by-path
+    /// ("by-path:pci-0000:00:14.0-usb-0:2:1.0-port0"). "auto" finds all boards
+    /// connected via USB. Default: "/dev/ttyUSB0"
+    #[argh(option, short = 'c')]
+    coolbox_port: Vec<BoardSpec>,
 
     /// REST API host. Default: 127.0.0.1
     #[argh(option, short = 'h', default = "\"127.0.0.1\".to_string()")]
@@ -29,16 +54,335 @@ struct WebCli {
     #[argh(option, short = 'p', default = "65231")]
     api_port: u16,
 
-    /// a dummy mode, when a fake is used instead of a real device
+    /// a dummy mode, when an emulator of the firmware is used instead of a real device
     #[argh(switch, short = 'd')]
     dummy: bool,
+
+    /// record everything sent to and received from the board into a capture file (JSON lines).
+    /// With multiple boards, the board's id is added to the file name.
+    #[argh(option)]
+    record: Option<PathBuf>,
+
+    /// keep the last update accepted for the board in this file (JSON), and send it to the board
+    /// again once the service is restarted. With multiple boards, the board's id is added
+    /// to the file name.
+    #[argh(option)]
+    state_file: Option<PathBuf>,
+
+    /// replay a capture file made with --record instead of talking to a real device
+    #[argh(option)]
+    replay: Option<PathBuf>,
+
+    /// number of GPUs in the rig simulated in the dummy mode. Default: 2
+    #[argh(option, default = "2")]
+    sim_gpus: usize,
+
+    /// ambient temperature of the rig simulated in the dummy mode, °C. Default: 25
+    #[argh(option, default = "25.0")]
+    sim_ambient_temp: f64,
+
+    /// power dissipated by every GPU of the rig simulated in the dummy mode, W. Default: 200
+    #[argh(option, default = "200.0")]
+    sim_heat_load: f64,
+
+    /// read GPU temperatures from hwmon and send them to the boards, instead of waiting
+    /// for them at /api/update
+    #[argh(switch)]
+    hwmon: bool,
+
+    /// directory with the hwmon devices. Default: "/sys/class/hwmon"
+    #[argh(option, default = "PathBuf::from(hwmon::DEFAULT_HWMON_ROOT)")]
+    hwmon_root: PathBuf,
+
+    /// label of the hwmon sensors measuring GPU cores. Can be repeated, the first label found
+    /// is used. Default: "edge"
+    #[argh(option)]
+    hwmon_core_label: Vec<String>,
+
+    /// label of the hwmon sensors measuring GPU VRAM. Can be repeated, the first label found
+    /// is used. Default: "mem"
+    #[argh(option)]
+    hwmon_mem_label: Vec<String>,
+
+    /// read GPU temperatures from the output of this shell command, like
+    /// "nvidia-smi --query-gpu=temperature.gpu,temperature.memory --format=csv",
+    /// and send them to the boards
+    #[argh(option)]
+    temp_command: Option<String>,
+
+    /// format of the output of --temp-command: csv or json. Default: csv
+    #[argh(option, default = "external::OutputFormat::Csv")]
+    temp_command_format: external::OutputFormat,
+
+    /// how often to send the collected GPU temperatures to the boards, in seconds. Default: 5
+    #[argh(option, default = "5")]
+    collect_interval: u64,
+
+    /// let the service control the fans through a curve instead of the firmware's auto mode:
+    /// "<temp>:<speed>,..." for the GPU cores, optionally followed by "/<temp>:<speed>,..."
+    /// for the VRAM, like "40:20,60:50,80:100/70:30,95:100". Can be prefixed with the board's id
+    /// ("rig1=40:20,80:100") and repeated, otherwise applies to all boards
+    #[argh(option)]
+    fan_curve: Vec<BoardFanCurve>,
+
+    /// let a PID controller keep the hottest GPU at the targets given to /api/update, choosing
+    /// the fan speed instead of the firmware's auto mode. The settings are comma-separated, like
+    /// "kp=4,ki=0.1,kd=0,min_speed=20,max_speed=100,max_step=5", the ones not given keep these
+    /// defaults. Applies to all boards, the settings can be tuned for every board via /api/pid
+    #[argh(option)]
+    pid: Option<PidSettings>,
+
+    /// switch the fans to --failsafe-speed if no GPU temperatures have come for this many
+    /// seconds, via /api/update or the collector. The normal control resumes as soon as
+    /// the temperatures come again. Must be above zero, and longer than --collect-interval
+    /// if the temperatures are collected by the service
+    #[argh(option, from_str_fn(failsafe::parse_timeout))]
+    failsafe_timeout: Option<u64>,
+
+    /// fan speed while no GPU temperatures are coming, in percent. Default: 100
+    #[argh(option, default = "100")]
+    failsafe_speed: u8,
+
+    /// what to send to the boards when the service is stopped by SIGTERM or SIGINT:
+    /// "full-speed", "auto" to hand the control back to the firmware with safe targets,
+    /// "auto:<core>/<mem>" with the targets given in °C, like "auto:70/90", or "none".
+    /// The watchdog is turned off either way. Default: full-speed
+    #[argh(option, default = "ShutdownAction::FullSpeed")]
+    on_shutdown: ShutdownAction,
+
+    /// id of the board a command is meant for. Default: the first board
+    #[argh(option, short = 'b')]
+    board: Option<String>,
+
+    /// make a command talk straight to the board given with --coolbox-port,
+    /// instead of the service running at --api-host and --api-port
+    #[argh(switch)]
+    direct: bool,
+
+    /// print the results of a command as JSON instead of a table
+    #[argh(switch)]
+    json: bool,
+
+    #[argh(subcommand)]
+    command: Option<ClientCommand>,
+}
+
+/// A fan curve given on the command line, for one board or, without an id, for all of them.
+#[derive(Debug, Clone, PartialEq)]
+struct BoardFanCurve {
+    board_id: Option<String>,
+    curve: FanCurve,
+}
+
+impl std::str::FromStr for BoardFanCurve {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (board_id, curve) = match spec.split_once('=') {
+            Some((board_id, curve)) => (Some(board_id.to_string()), curve),
+            None => (None, spec),
+        };
+        Ok(Self {
+            board_id,
+            curve: curve.parse()?,
+        })
+    }
+}
+
+/// The boards given on the command line, with "auto" expanded.
+fn board_specs(cli: &WebCli) -> io::Result<Vec<BoardSpec>> {
+    let board_specs = if cli.coolbox_port.is_empty() {
+        vec!["/dev/ttyUSB0".parse::<BoardSpec>().map_err(io::Error::other)?]
+    } else {
+        cli.coolbox_port.clone()
+    };
+    if cli.dummy || cli.replay.is_some() {
+        Ok(board_specs)
+    } else {
+        expand_auto_specs(board_specs)
+    }
+}
+
+/// Connects to the board, or to its stand-in in the dummy and replay modes.
+fn open_board(
+    cli: &WebCli,
+    spec: &BoardSpec,
+    capture: Option<CaptureRecorder>,
+) -> io::Result<CoolboxAutofan> {
+    let autofan = if let Some(replay_path) = &cli.replay {
+        CoolboxAutofan::replay(replay_path).map_err(|e| {
+            io::Error::other(format!("Unable to replay {}: {}", replay_path.display(), e))
+        })?
+    } else if cli.dummy {
+        CoolboxAutofan::dummy(
+            SimulationSettings {
+                gpus: cli.sim_gpus,
+                ambient_temp: cli.sim_ambient_temp,
+                heat_load: cli.sim_heat_load,
+            },
+            capture,
+        )?
+    } else {
+        CoolboxAutofan::open(spec.port.clone(), capture).map_err(|e| {
+            io::Error::other(format!("Unable to open terminal {}: {}", &spec.port, e))
+        })?
+    };
+    log::info!(
+        "Connected to the coolbox tty port {} as board {:?}: {:?}",
+        &spec.port,
+        &spec.id,
+        autofan.identity()
+    );
+    Ok(autofan)
+}
+
+/// Runs a client command against the service or, with --direct, the board itself.
+fn run_client(cli: &WebCli, command: ClientCommand) -> io::Result<()> {
+    let format = if cli.json {
+        OutputFormat::Json
+    } else {
+        OutputFormat::Table
+    };
+    let backend = if cli.direct {
+        let board_specs = board_specs(cli)?;
+        let spec = match &cli.board {
+            Some(board_id) => board_specs.iter().find(|spec| &spec.id == board_id),
+            None => board_specs.first(),
+        }
+        .ok_or_else(|| io::Error::other("No such board"))?;
+        Backend::Board(open_board(cli, spec, None)?)
+    } else {
+        let board_path = match &cli.board {
+            Some(board_id) => format!("/boards/{}", board_id),
+            None => String::new(),
+        };
+        Backend::Service {
+            api_url: format!("http://{}:{}/api{}", cli.api_host, cli.api_port, board_path),
+        }
+    };
+    client::run(command, backend, format)
+}
+
+/// The fail-safe settings, if the fail-safe is asked for. The timeout itself is checked
+/// by [`failsafe::parse_timeout`], here it's checked against the other options.
+fn failsafe_settings(cli: &WebCli) -> io::Result<Option<FailsafeSettings>> {
+    let Some(timeout_s) = cli.failsafe_timeout else {
+        return Ok(None);
+    };
+    if cli.failsafe_speed > 100 {
+        return Err(io::Error::other("The fail-safe fan speed can't be above 100%"));
+    }
+    let collecting = cli.hwmon || cli.temp_command.is_some();
+    if collecting && timeout_s <= cli.collect_interval.max(1) {
+        return Err(io::Error::other(
+            "The fail-safe timeout must be longer than --collect-interval",
+        ));
+    }
+    Ok(Some(FailsafeSettings {
+        timeout_s,
+        speed: cli.failsafe_speed,
+    }))
+}
+
+/// The source of GPU temperatures collected by the service itself, if any.
+fn temperature_source(cli: &WebCli) -> io::Result<Option<Box<dyn TemperatureSource>>> {
+    if let Some(command) = &cli.temp_command {
+        if cli.hwmon {
+            return Err(io::Error::other("Only one of --hwmon and --temp-command can be given"));
+        }
+        return Ok(Some(Box::new(CommandSource::new(CommandSettings {
+            command: command.clone(),
+            format: cli.temp_command_format,
+            ..Default::default()
+        }))));
+    }
+    if !cli.hwmon {
+        return Ok(None);
+    }
+    let defaults = HwmonSettings::default();
+    let or_default = |labels: &Vec<String>, default: Vec<String>| {
+        if labels.is_empty() {
+            default
+        } else {
+            labels.clone()
+        }
+    };
+    Ok(Some(Box::new(HwmonSource::new(HwmonSettings {
+        root: cli.hwmon_root.clone(),
+        core_labels: or_default(&cli.hwmon_core_label, defaults.core_labels),
+        mem_labels: or_default(&cli.hwmon_mem_label, defaults.mem_labels),
+    }))))
+}
+
+/// Replaces every "auto" port with the boards found on the USB serial ports,
+/// except those already given explicitly.
+fn expand_auto_specs(specs: Vec<BoardSpec>) -> io::Result<Vec<BoardSpec>> {
+    if !specs.iter().any(|spec| spec.port == PortSelector::Auto) {
+        return Ok(specs);
+    }
+    let explicit_ports = specs
+        .iter()
+        .filter(|spec| spec.port != PortSelector::Auto)
+        .filter_map(|spec| Some((spec.port.resolve().ok()?, spec.id.clone())))
+        .collect::<Vec<_>>();
+    log::info!("Looking for Coolbox boards...");
+    let found = discovery::discover(&explicit_ports)?
+        .into_iter()
+        .filter(|port| port.is_coolbox && port.board_id.is_none())
+        .map(|port| port.port)
+        .collect::<Vec<_>>();
+    if found.is_empty() {
+        log::warn!("No Coolbox boards have been found");
+    }
+    let mut expanded = Vec::new();
+    for spec in specs {
+        if spec.port != PortSelector::Auto {
+            expanded.push(spec);
+            continue;
+        }
+        for (i, port) in found.iter().enumerate() {
+            let port = PortSelector::Path(port.clone());
+            let id = if spec.id == PortSelector::Auto.default_board_id() {
+                // No id given, naming the board after its port
+                port.default_board_id()
+            } else if i == 0 {
+                spec.id.clone()
+            } else {
+                format!("{}-{}", spec.id, i + 1)
+            };
+            expanded.push(BoardSpec { id, port });
+        }
+    }
+    Ok(expanded)
+}
+
+/// With multiple boards every board gets its own capture and state files,
+/// like "capture.rig1.jsonl".
+fn board_file_path(path: &Path, board_id: &str, board_count: usize) -> PathBuf {
+    if board_count < 2 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, board_id, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, board_id),
+    };
+    path.with_file_name(file_name)
 }
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
-    let cli: WebCli = argh::from_env();
+    let mut cli: WebCli = argh::from_env();
     env_logger::init();
 
+    if let Some(command) = cli.command.take() {
+        if let Err(e) = run_client(&cli, command) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     #[derive(OpenApi)]
     #[openapi(
         tags(
@@ -47,51 +391,117 @@ async fn main() -> io::Result<()> {
     )]
     struct ApiDoc;
 
-    let autofan = web::Data::new(if cli.dummy {
-        CoolboxAutofan::dummy()?
-    } else {
-        CoolboxAutofan::try_from(cli.coolbox_port.clone()).map_err(|e| {
-            io::Error::other(
-                format!(
-                    "Unable to open terminal {}: {}",
-                    &cli.coolbox_port,
-                    e
-                ),
-            )
-        })?
+    let failsafe = failsafe_settings(&cli)?;
+    let board_specs = board_specs(&cli)?;
+    let board_count = board_specs.len();
+    let mut registry = BoardRegistry::default();
+    for spec in board_specs {
+        let capture = match &cli.record {
+            Some(path) => {
+                let path = board_file_path(path, &spec.id, board_count);
+                log::info!("Recording board {:?} into {}", &spec.id, path.display());
+                Some(CaptureRecorder::create(&path)?)
+            }
+            None => None,
+        };
+        let autofan = open_board(&cli, &spec, capture)?;
+        let fan_curve = cli
+            .fan_curve
+            .iter()
+            .find(|curve| curve.board_id.as_ref() == Some(&spec.id))
+            .or_else(|| cli.fan_curve.iter().find(|curve| curve.board_id.is_none()));
+        if failsafe.is_some() {
+            autofan.set_failsafe(failsafe);
+        }
+        if let Some(settings) = cli.pid {
+            if fan_curve.is_some() {
+                return Err(io::Error::other("Only one of --fan-curve and --pid can be given"));
+            }
+            log::info!("Controlling the fans of board {:?} by PID {:?}", &spec.id, settings);
+            futures::executor::block_on(autofan.set_pid(Some(settings)))
+                .map_err(io::Error::from)?;
+        }
+        if let Some(fan_curve) = fan_curve {
+            log::info!("Controlling the fans of board {:?} by {:?}", &spec.id, fan_curve.curve);
+            futures::executor::block_on(autofan.set_fan_curve(Some(fan_curve.curve.clone())))
+                .map_err(io::Error::from)?;
+        }
+        if let Some(path) = &cli.state_file {
+            let path = board_file_path(path, &spec.id, board_count);
+            log::info!("Keeping the state of board {:?} in {}", &spec.id, path.display());
+            if let Err(e) = autofan.use_state_file(StateFile::new(&path)) {
+                log::warn!("Unable to restore the state from {}: {}", path.display(), e);
+            }
+        }
+        registry.add(spec.id, autofan)?;
+    }
+    let registry = web::Data::new(registry);
+    let collector = temperature_source(&cli)?.map(|source| {
+        let interval = Duration::from_secs(cli.collect_interval.max(1));
+        log::info!("Sending the collected GPU temperatures every {:?}", interval);
+        TemperatureCollector::spawn(source, registry.boards().cloned().collect(), interval)
     });
-    log::info!("Connected to the coolbox tty port {}", &cli.coolbox_port);
 
     log::info!(
         "Launching REST API at http://{host}:{port}",
         host = cli.api_host,
         port = cli.api_port
     );
+    let server_registry = registry.clone();
     HttpServer::new(move || {
-        let autofan_clone = autofan.clone();
+        let registry_clone = server_registry.clone();
+        let metrics_registry = server_registry.clone();
         let api_service = utoipa_actix_web::scope("/api").configure(
             |config: &mut utoipa_actix_web::service_config::ServiceConfig| {
                 config
-                    .app_data(autofan_clone)
-                    .service(api::health)
-                    .service(api::fan_check)
-                    .service(api::plain_message)
-                    .service(api::update)
-                    .service(api::diagnostic)
-                    .service(api::watch);
+                    .app_data(registry_clone)
+                    .service(api::list_boards)
+                    .service(api::discover)
+                    .service(
+                        utoipa_actix_web::scope("/boards/{board_id}")
+                            .configure(api::board_services),
+                    )
+                    // The original single-board endpoints, serving the first board.
+                    // Left out of the API docs to avoid duplicating every endpoint there.
+                    .map(|config| {
+                        api::board_services(
+                            &mut utoipa_actix_web::service_config::ServiceConfig::new(config),
+                        );
+                        config
+                    });
             },
         );
 
-        App::new()
+        let app = App::new()
             .into_utoipa_app()
             .openapi(ApiDoc::openapi())
-            .map(|app| app.wrap(middleware::Logger::default()))
+            .map(|app| app.wrap(middleware::Logger::default()).app_data(metrics_registry))
             .service(api_service)
-            .openapi_service(|api| SwaggerUi::new("/docs/{_:.*}").url("/openapi.json", api))
-            .into_app()
+            .service(api::metrics);
+        #[cfg(feature = "swagger-ui")]
+        let app =
+            app.openapi_service(|api| SwaggerUi::new("/docs/{_:.*}").url("/openapi.json", api));
+        app.into_app()
     })
+    // A client closing the connection cancels its request, including the command waiting in the queue
+    .h1_allow_half_closed(false)
+    // Don't let /api/watch clients hold the boards in the state they are in for long
+    .shutdown_timeout(SHUTDOWN_TIMEOUT_S)
     .bind((cli.api_host, cli.api_port))?
     .workers(2)
     .run()
-    .await
+    .await?;
+
+    // Actix has stopped on a signal. Nothing else may talk to the boards after the final command
+    if let Some(mut collector) = collector {
+        collector.stop();
+    }
+    let final_command = cli.on_shutdown.command();
+    for board in registry.boards() {
+        log::info!("Shutting down board {:?}", board.id);
+        if let Err(e) = board.autofan.shutdown(final_command.as_ref()) {
+            log::error!("Board {:?} has failed: {}", board.id, e);
+        }
+    }
+    Ok(())
 }
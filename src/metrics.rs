@@ -0,0 +1,304 @@
+//! Metrics of the boards in the Prometheus text format, served at `/metrics`:
+//! ```text
+//! # HELP coolbox_fan_pwm_percent Fan speed reported by the board, in percent.
+//! # TYPE coolbox_fan_pwm_percent gauge
+//! coolbox_fan_pwm_percent{board="rig1"} 35
+//! ```
+//! The temperatures and the targets are the last ones requested through `/api/update`
+//! (or sent by the collector). The fan mode and speed are the ones last sent to the board,
+//! chosen by the fan curve, the PID controller or the fail-safe if any of them is in charge.
+//! The telemetry gauges are only there while the board prints telemetry, that is
+//! in the service mode.
+
+use std::fmt::Write;
+
+use crate::command::{Command, FanMode};
+use crate::registry::Board;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MetricKind {
+    Gauge,
+    Counter,
+}
+
+/// The samples of one metric, which have to come together after its HELP and TYPE lines.
+#[derive(Debug)]
+struct MetricFamily {
+    name: &'static str,
+    help: &'static str,
+    kind: MetricKind,
+    samples: Vec<(String, f64)>,
+}
+
+/// Collects the samples of all the metrics, and renders them in the text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    families: Vec<MetricFamily>,
+}
+
+impl Metrics {
+    fn add(
+        &mut self,
+        kind: MetricKind,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        let index = match self.families.iter().position(|family| family.name == name) {
+            Some(index) => index,
+            None => {
+                self.families.push(MetricFamily {
+                    name,
+                    help,
+                    kind,
+                    samples: Vec::new(),
+                });
+                self.families.len() - 1
+            }
+        };
+        let labels = labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape_label_value(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+        self.families[index].samples.push((labels, value));
+    }
+
+    pub fn gauge(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        self.add(MetricKind::Gauge, name, help, labels, value);
+    }
+
+    pub fn counter(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        self.add(MetricKind::Counter, name, help, labels, value);
+    }
+
+    /// Adds the metrics of the board, labelled with its id.
+    pub fn add_board(&mut self, board: &Board) {
+        let labels = [("board", board.id.as_str())];
+        let autofan = &board.autofan;
+        self.gauge(
+            "coolbox_up",
+            "Whether the service is listening to the board.",
+            &labels,
+            autofan.is_listener_alive() as u8 as f64,
+        );
+
+        if let Some(Command::Update(update)) = autofan.desired_state() {
+            let temperatures = [
+                (
+                    "coolbox_gpu_core_temperature_celsius",
+                    "The last GPU core temperature sent to the board.",
+                    update.gpu_temp.unwrap_or_default(),
+                ),
+                (
+                    "coolbox_gpu_mem_temperature_celsius",
+                    "The last GPU VRAM temperature sent to the board.",
+                    update.gpu_mem.unwrap_or_default(),
+                ),
+            ];
+            for (name, help, temperatures) in temperatures {
+                for (gpu, temperature) in temperatures.into_iter().enumerate() {
+                    let gpu = gpu.to_string();
+                    let labels = [labels[0], ("gpu", gpu.as_str())];
+                    self.gauge(name, help, &labels, temperature as f64);
+                }
+            }
+            if let Some(target_temp) = update.target_temp {
+                self.gauge(
+                    "coolbox_target_core_temperature_celsius",
+                    "Target GPU core temperature.",
+                    &labels,
+                    target_temp as f64,
+                );
+            }
+            if let Some(target_mem) = update.target_mem {
+                self.gauge(
+                    "coolbox_target_mem_temperature_celsius",
+                    "Target GPU VRAM temperature.",
+                    &labels,
+                    target_mem as f64,
+                );
+            }
+        }
+
+        if let Some(Command::Update(update)) = autofan.applied_state() {
+            if let Some(fan_mode) = update.fan_mode {
+                self.gauge(
+                    "coolbox_fan_mode",
+                    "Fan mode sent to the board: 1 - manual, 2 - auto.",
+                    &labels,
+                    i32::from(fan_mode) as f64,
+                );
+            }
+            if let Some(speed) = update.manual_fan_speed
+                && update.fan_mode == Some(FanMode::Manual)
+            {
+                self.gauge(
+                    "coolbox_manual_fan_speed_percent",
+                    "Fan speed sent to the board in the manual mode, in percent.",
+                    &labels,
+                    speed as f64,
+                );
+            }
+        }
+
+        if let Some(telemetry) = autofan.latest_telemetry() {
+            self.gauge(
+                "coolbox_fan_pwm_percent",
+                "Fan speed reported by the board, in percent.",
+                &labels,
+                telemetry.fan_pwm as f64,
+            );
+            self.gauge(
+                "coolbox_ocr0b",
+                "Value of the OCR0B register, the PWM duty of the fans.",
+                &labels,
+                telemetry.ocr0b as f64,
+            );
+            self.gauge(
+                "coolbox_ocr0a",
+                "Value of the OCR0A register, the top of the PWM counter.",
+                &labels,
+                telemetry.ocr0a_max as f64,
+            );
+            if telemetry.ocr0a_max > 0 {
+                self.gauge(
+                    "coolbox_fan_duty_ratio",
+                    "PWM duty of the fans, OCR0B/OCR0A.",
+                    &labels,
+                    telemetry.ocr0b as f64 / telemetry.ocr0a_max as f64,
+                );
+            }
+            self.gauge(
+                "coolbox_pwm_add",
+                "The last step the auto mode has applied to the fan speed.",
+                &labels,
+                telemetry.pwm_add as f64,
+            );
+            self.gauge(
+                "coolbox_cnt",
+                "Internal counter of the control loop of the board.",
+                &labels,
+                telemetry.cnt as f64,
+            );
+        }
+
+        let traffic = autofan.traffic_status();
+        self.counter(
+            "coolbox_commands_sent_total",
+            "Commands written to the board.",
+            &labels,
+            traffic.commands_sent as f64,
+        );
+        self.counter(
+            "coolbox_command_timeouts_total",
+            "Commands the board hasn't replied to in time.",
+            &labels,
+            traffic.command_timeouts as f64,
+        );
+        self.counter(
+            "coolbox_listener_restarts_total",
+            "How many times the listener has been started again on a reopened port.",
+            &labels,
+            traffic.listener_restarts as f64,
+        );
+        self.counter(
+            "coolbox_serial_bytes_read_total",
+            "Bytes read from the serial port of the board.",
+            &labels,
+            traffic.bytes_read as f64,
+        );
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for family in &self.families {
+            let kind = match family.kind {
+                MetricKind::Gauge => "gauge",
+                MetricKind::Counter => "counter",
+            };
+            writeln!(text, "# HELP {} {}", family.name, family.help).unwrap();
+            writeln!(text, "# TYPE {} {}", family.name, kind).unwrap();
+            for (labels, value) in &family.samples {
+                writeln!(text, "{}{{{}}} {}", family.name, labels, value).unwrap();
+            }
+        }
+        text
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::autofan::CoolboxAutofan;
+    use crate::command::Update;
+    use crate::control::FanCurve;
+    use crate::simulation::SimulationSettings;
+
+    #[test]
+    fn reports_fan_speed_chosen_by_curve() {
+        let board = Board {
+            id: "rig1".to_string(),
+            autofan: CoolboxAutofan::dummy(SimulationSettings::default(), None).unwrap(),
+        };
+        let curve = "40:20,80:100".parse::<FanCurve>().unwrap();
+        block_on(board.autofan.set_fan_curve(Some(curve))).unwrap();
+        let update = Update::for_temperatures(vec![50, 60], vec![], 65, 90, None, None);
+        block_on(board.autofan.send_desired_state(update.into())).unwrap();
+
+        let mut metrics = Metrics::default();
+        metrics.add_board(&board);
+        let text = metrics.render();
+        board.autofan.join().unwrap();
+        for sample in [
+            "coolbox_up{board=\"rig1\"} 1",
+            "coolbox_gpu_core_temperature_celsius{board=\"rig1\",gpu=\"1\"} 60",
+            "coolbox_target_core_temperature_celsius{board=\"rig1\"} 65",
+            "coolbox_fan_mode{board=\"rig1\"} 1",
+            "coolbox_manual_fan_speed_percent{board=\"rig1\"} 60",
+            "coolbox_commands_sent_total{board=\"rig1\"} 1",
+        ] {
+            assert!(text.lines().any(|line| line == sample), "{} in\n{}", sample, text);
+        }
+    }
+
+    #[test]
+    fn groups_samples_by_metric() {
+        let mut metrics = Metrics::default();
+        metrics.gauge("coolbox_temp", "Temperature.", &[("board", "rig1")], 45.0);
+        metrics.counter("coolbox_sent_total", "Sent.", &[("board", "rig1")], 3.0);
+        metrics.gauge("coolbox_temp", "Temperature.", &[("board", "a\"b\\c")], 47.5);
+        assert_eq!(
+            metrics.render(),
+            "# HELP coolbox_temp Temperature.\n\
+             # TYPE coolbox_temp gauge\n\
+             coolbox_temp{board=\"rig1\"} 45\n\
+             coolbox_temp{board=\"a\\\"b\\\\c\"} 47.5\n\
+             # HELP coolbox_sent_total Sent.\n\
+             # TYPE coolbox_sent_total counter\n\
+             coolbox_sent_total{board=\"rig1\"} 3\n"
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use std::io::{self};
+use std::sync::Arc;
+
+use super::autofan::CoolboxAutofan;
+use super::identity::PortSelector;
+
+/// A board known to the service.
+pub struct Board {
+    pub id: String,
+    pub autofan: CoolboxAutofan,
+}
+
+/// A board as given on the command line: `[<id>=]<port>`, for instance `rig1=/dev/ttyUSB0`
+/// or `rig1=serial:A50285BI` (see [`PortSelector`]). The id is an alias of the board
+/// used in the API. If no id is given, the file name of the port is used (`ttyUSB0`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoardSpec {
+    pub id: String,
+    pub port: PortSelector,
+}
+
+impl std::str::FromStr for BoardSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (id, port) = match spec.split_once('=') {
+            Some((id, port)) => (id.to_string(), port.parse::<PortSelector>()?),
+            None => {
+                let port = spec.parse::<PortSelector>()?;
+                (port.default_board_id(), port)
+            }
+        };
+        if id.is_empty()
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        {
+            return Err(format!(
+                "Invalid board id {:?}: only latin letters, digits, '-', '_' and '.' are allowed",
+                id
+            ));
+        }
+        Ok(Self { id, port })
+    }
+}
+
+/// All boards served by the daemon, in the order they were given on the command line.
+#[derive(Default)]
+pub struct BoardRegistry {
+    boards: Vec<Arc<Board>>,
+}
+
+impl BoardRegistry {
+    pub fn add(&mut self, id: String, autofan: CoolboxAutofan) -> io::Result<()> {
+        if self.get(&id).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Board {:?} is given more than once", id),
+            ));
+        }
+        self.boards.push(Arc::new(Board { id, autofan }));
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Board>> {
+        self.boards.iter().find(|board| board.id == id).cloned()
+    }
+
+    /// The board addressed by the short `/api/...` endpoints, which is the first one.
+    pub fn default_board(&self) -> Option<Arc<Board>> {
+        self.boards.first().cloned()
+    }
+
+    pub fn boards(&self) -> impl Iterator<Item = &Arc<Board>> {
+        self.boards.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::SimulationSettings;
+
+    #[test]
+    fn parses_specs() {
+        assert_eq!(
+            "rig1=/dev/ttyUSB0".parse(),
+            Ok(BoardSpec {
+                id: "rig1".to_string(),
+                port: PortSelector::Path("/dev/ttyUSB0".to_string()),
+            })
+        );
+        assert_eq!(
+            "rig-2.a=serial:A50285BI".parse(),
+            Ok(BoardSpec {
+                id: "rig-2.a".to_string(),
+                port: PortSelector::SerialNumber("A50285BI".to_string()),
+            })
+        );
+        assert_eq!(
+            "/dev/ttyACM0".parse(),
+            Ok(BoardSpec {
+                id: "ttyACM0".to_string(),
+                port: PortSelector::Path("/dev/ttyACM0".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_specs() {
+        for spec in [
+            "=/dev/ttyUSB0",
+            "rig 1=/dev/ttyUSB0",
+            "rig/1=auto",
+            "rig1=usb:xyz:1",
+            "rig1=",
+        ] {
+            assert!(spec.parse::<BoardSpec>().is_err(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_ids() {
+        let dummy = || CoolboxAutofan::dummy(SimulationSettings::default(), None).unwrap();
+        let mut registry = BoardRegistry::default();
+        registry.add("rig1".to_string(), dummy()).unwrap();
+        let error = registry.add("rig1".to_string(), dummy()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(registry.boards().count(), 1);
+    }
+}
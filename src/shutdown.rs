@@ -0,0 +1,131 @@
+//! Leaving the board in a safe state when the service stops.
+//!
+//! Once the service is gone nobody sends the temperatures or resets the watchdog, so the last
+//! command the board gets decides how the rig is cooled until the service is back. It either
+//! spins the fans at full speed, or hands the control back to the firmware's auto mode with
+//! safe targets. Either way the watchdog is turned off, so the rig doesn't get reset while
+//! the service is stopped for an upgrade.
+
+use crate::command::{Command, FanMode, Update};
+
+/// The targets of the firmware's auto mode, unless others are given.
+pub const DEFAULT_SHUTDOWN_TARGET_TEMP: i32 = 60;
+pub const DEFAULT_SHUTDOWN_TARGET_MEM: i32 = 90;
+
+/// What to send to the board when the service stops.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ShutdownAction {
+    /// Spin the fans at 100%.
+    #[default]
+    FullSpeed,
+    /// Switch to the firmware's auto mode with these targets.
+    Auto { target_temp: i32, target_mem: i32 },
+    /// Leave the board as it is.
+    Nothing,
+}
+
+impl ShutdownAction {
+    /// The final command, if any.
+    pub fn command(&self) -> Option<Command> {
+        let update = match *self {
+            Self::FullSpeed => Update {
+                fan_mode: Some(FanMode::Manual),
+                manual_fan_speed: Some(100),
+                ..Default::default()
+            },
+            Self::Auto {
+                target_temp,
+                target_mem,
+            } => Update {
+                target_temp: Some(target_temp),
+                target_mem: Some(target_mem),
+                fan_mode: Some(FanMode::Auto),
+                ..Default::default()
+            },
+            Self::Nothing => return None,
+        };
+        Some(
+            Update {
+                watchdog: Some(false),
+                ..update
+            }
+            .into(),
+        )
+    }
+}
+
+/// Parses `full-speed`, `none`, `auto`, or `auto:<core>/<mem>` with the targets in °C,
+/// like `auto:70/90`.
+impl std::str::FromStr for ShutdownAction {
+    type Err = String;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid shutdown action {:?}, expected full-speed, none, auto \
+                or auto:<core>/<mem>, like auto:70/90",
+                action
+            )
+        };
+        match action.split_once(':') {
+            None => match action {
+                "full-speed" => Ok(Self::FullSpeed),
+                "none" => Ok(Self::Nothing),
+                "auto" => Ok(Self::Auto {
+                    target_temp: DEFAULT_SHUTDOWN_TARGET_TEMP,
+                    target_mem: DEFAULT_SHUTDOWN_TARGET_MEM,
+                }),
+                _ => Err(invalid()),
+            },
+            Some(("auto", targets)) => {
+                let (target_temp, target_mem) = targets.split_once('/').ok_or_else(invalid)?;
+                Ok(Self::Auto {
+                    target_temp: target_temp.trim().parse().map_err(|_| invalid())?,
+                    target_mem: target_mem.trim().parse().map_err(|_| invalid())?,
+                })
+            }
+            Some(..) => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_actions() {
+        assert_eq!("full-speed".parse(), Ok(ShutdownAction::FullSpeed));
+        assert_eq!("none".parse(), Ok(ShutdownAction::Nothing));
+        assert_eq!(
+            "auto".parse(),
+            Ok(ShutdownAction::Auto {
+                target_temp: DEFAULT_SHUTDOWN_TARGET_TEMP,
+                target_mem: DEFAULT_SHUTDOWN_TARGET_MEM
+            })
+        );
+        assert_eq!(
+            "auto:70/95".parse(),
+            Ok(ShutdownAction::Auto {
+                target_temp: 70,
+                target_mem: 95
+            })
+        );
+        assert!("auto:70".parse::<ShutdownAction>().is_err());
+        assert!("half-speed".parse::<ShutdownAction>().is_err());
+    }
+
+    #[test]
+    fn turns_watchdog_off() {
+        assert_eq!(
+            ShutdownAction::FullSpeed.command(),
+            Some(Command::Update(Update {
+                fan_mode: Some(FanMode::Manual),
+                manual_fan_speed: Some(100),
+                watchdog: Some(false),
+                ..Default::default()
+            }))
+        );
+        assert_eq!(ShutdownAction::Nothing.command(), None);
+    }
+}
@@ -0,0 +1,166 @@
+//! The rig simulated behind a board emulated in the dummy mode.
+//!
+//! The emulated firmware ([`crate::emulator`]) advances a [`ThermalPlant`] at the fan speed
+//! it's running at, and the GPUs of the plant heat up and cool down accordingly, so the fan
+//! control can be tried out without a rig.
+
+use std::sync::{Arc, Mutex};
+
+/// Heat transferred by a GPU core to the air per 1°C of difference, when the fans are stopped (W/°C).
+const CORE_PASSIVE_CONDUCTANCE: f64 = 2.0;
+/// Additional heat transfer of a GPU core at 100% fan speed (W/°C).
+const CORE_FAN_CONDUCTANCE: f64 = 8.0;
+/// Heat capacity of a GPU core with its heatsink (J/°C).
+const CORE_HEAT_CAPACITY: f64 = 300.0;
+/// The share of the heat load dissipated by the VRAM.
+const MEM_HEAT_SHARE: f64 = 0.3;
+const MEM_PASSIVE_CONDUCTANCE: f64 = 0.5;
+const MEM_FAN_CONDUCTANCE: f64 = 2.0;
+const MEM_HEAT_CAPACITY: f64 = 150.0;
+/// Every next GPU gets this much more load than the previous one, so they don't look all the same.
+const LOAD_SPREAD_PER_GPU: f64 = 0.05;
+
+/// Parameters of the simulated rig.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SimulationSettings {
+    /// Number of simulated GPUs.
+    #[cfg_attr(feature = "openapi", schema(example = 2))]
+    pub gpus: usize,
+    /// Temperature of the air around the rig, °C.
+    #[cfg_attr(feature = "openapi", schema(example = 25.0))]
+    pub ambient_temp: f64,
+    /// Power dissipated by every GPU, W.
+    #[cfg_attr(feature = "openapi", schema(example = 200.0))]
+    pub heat_load: f64,
+}
+
+impl Default for SimulationSettings {
+    fn default() -> Self {
+        Self {
+            gpus: 2,
+            ambient_temp: 25.0,
+            heat_load: 200.0,
+        }
+    }
+}
+
+/// The current state of the simulated rig.
+#[derive(Debug, Clone, serde::Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SimulationState {
+    #[serde(flatten)]
+    pub settings: SimulationSettings,
+    /// Fan speed the simulation is running at, in percent.
+    pub fan_pwm: i32,
+    /// Simulated GPU core temperatures, one per GPU, rounded the way GPU drivers report them.
+    pub core_temp: Vec<i32>,
+    /// Simulated GPU VRAM temperatures, one per GPU.
+    pub mem_temp: Vec<i32>,
+}
+
+/// A lumped thermal model of a mining rig: every GPU core and its VRAM get heated by the load
+/// and cooled by the air, the more the faster the fans spin.
+#[derive(Debug, Clone)]
+pub struct ThermalPlant {
+    settings: SimulationSettings,
+    fan_pwm: i32,
+    core_temp: Vec<f64>,
+    mem_temp: Vec<f64>,
+}
+
+pub type SharedThermalPlant = Arc<Mutex<ThermalPlant>>;
+
+impl ThermalPlant {
+    pub fn new(settings: SimulationSettings) -> Self {
+        let mut plant = Self {
+            fan_pwm: 0,
+            core_temp: Vec::new(),
+            mem_temp: Vec::new(),
+            settings: settings.clone(),
+        };
+        plant.configure(settings);
+        plant
+    }
+
+    /// Changes the parameters of the rig. The temperatures of the GPUs that were
+    /// already there are kept, the new ones start at the ambient temperature.
+    pub fn configure(&mut self, settings: SimulationSettings) {
+        self.core_temp.resize(settings.gpus, settings.ambient_temp);
+        self.mem_temp.resize(settings.gpus, settings.ambient_temp);
+        self.settings = settings;
+    }
+
+    /// Advances the simulation by `dt` seconds with the fans spinning at `fan_pwm` percent.
+    pub fn step(&mut self, dt: f64, fan_pwm: i32) {
+        self.fan_pwm = fan_pwm;
+        let fan = fan_pwm.clamp(0, 100) as f64 / 100.0;
+        let ambient = self.settings.ambient_temp;
+        for (i, (core, mem)) in self
+            .core_temp
+            .iter_mut()
+            .zip(self.mem_temp.iter_mut())
+            .enumerate()
+        {
+            let load = self.settings.heat_load * (1.0 + LOAD_SPREAD_PER_GPU * i as f64);
+            let core_conductance = CORE_PASSIVE_CONDUCTANCE + CORE_FAN_CONDUCTANCE * fan;
+            *core += dt * (load - core_conductance * (*core - ambient)) / CORE_HEAT_CAPACITY;
+            let mem_conductance = MEM_PASSIVE_CONDUCTANCE + MEM_FAN_CONDUCTANCE * fan;
+            *mem += dt * (load * MEM_HEAT_SHARE - mem_conductance * (*mem - ambient))
+                / MEM_HEAT_CAPACITY;
+        }
+    }
+
+    pub fn state(&self) -> SimulationState {
+        SimulationState {
+            settings: self.settings.clone(),
+            fan_pwm: self.fan_pwm,
+            core_temp: self.core_temp.iter().map(|t| t.round() as i32).collect(),
+            mem_temp: self.mem_temp.iter().map(|t| t.round() as i32).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(plant: &mut ThermalPlant, seconds: usize, fan_pwm: i32) {
+        for _ in 0..seconds {
+            plant.step(1.0, fan_pwm);
+        }
+    }
+
+    #[test]
+    fn reaches_steady_state() {
+        let mut plant = ThermalPlant::new(SimulationSettings::default());
+        assert_eq!(plant.state().core_temp, vec![25, 25]);
+        run(&mut plant, 3000, 50);
+        // ambient + load / conductance: the cores lose 6 W/°C and the VRAM 1.5 W/°C at 50%
+        let state = plant.state();
+        assert_eq!(state.fan_pwm, 50);
+        assert_eq!(state.core_temp, vec![58, 60]);
+        assert_eq!(state.mem_temp, vec![65, 67]);
+        let settled = plant.core_temp.clone();
+        run(&mut plant, 100, 50);
+        for (before, after) in settled.iter().zip(&plant.core_temp) {
+            assert!((before - after).abs() < 0.01, "{} -> {}", before, after);
+        }
+    }
+
+    #[test]
+    fn cools_down_when_fans_speed_up() {
+        let mut plant = ThermalPlant::new(SimulationSettings::default());
+        run(&mut plant, 3000, 50);
+        let mut previous = plant.clone();
+        for _ in 0..60 {
+            plant.step(1.0, 100);
+            assert!(plant.core_temp.iter().zip(&previous.core_temp).all(|(t, p)| t < p));
+            assert!(plant.mem_temp.iter().zip(&previous.mem_temp).all(|(t, p)| t < p));
+            previous = plant.clone();
+        }
+        run(&mut plant, 3000, 100);
+        assert_eq!(plant.state().core_temp, vec![45, 46]);
+        assert_eq!(plant.state().mem_temp, vec![49, 50]);
+    }
+}
@@ -0,0 +1,116 @@
+//! Persisting the desired state of the board across restarts of the service.
+//!
+//! The board forgets its settings whenever its port gets closed, so the last update accepted
+//! by the service is kept in a JSON file, and sent to the board again once it's connected:
+//! ```text
+//! {"saved_at":1760000000,"update":{"target_temp":70,"target_mem":90,"fan_mode":2,"manual_fan_speed":0,"watchdog":0}}
+//! ```
+//! Only the settings are kept, the temperatures would be stale by the time they get re-sent.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::command::Update;
+use crate::telemetry::unix_time_now;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PersistedState {
+    /// When the state has been saved, in seconds since the Unix epoch.
+    pub saved_at: u64,
+    pub update: Update,
+}
+
+/// The file keeping the desired state of a board.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the saved state. A missing file means there is nothing to restore.
+    pub fn load(&self) -> io::Result<Option<PersistedState>> {
+        let json = match fs::read_to_string(&self.path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Saves the settings of the update, leaving out the temperatures.
+    /// The file is replaced at once, so a crash can't leave it half-written.
+    pub fn save(&self, update: &Update) -> io::Result<()> {
+        let state = PersistedState {
+            saved_at: unix_time_now(),
+            update: update.settings(),
+        };
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, serde_json::to_vec(&state)?)?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A state file in a fresh directory, removed once the directory is dropped.
+    fn state_file() -> (TempDir, StateFile) {
+        let dir = TempDir::new().unwrap();
+        let file = StateFile::new(dir.path().join("rig1.json"));
+        (dir, file)
+    }
+
+    #[test]
+    fn keeps_settings_only() {
+        let (_dir, file) = state_file();
+        let update = Update::for_temperatures(vec![55, 61], vec![80], 70, 90, Some(5), None);
+        file.save(&update).unwrap();
+        let state = file.load().unwrap().unwrap();
+        let settings = Update { gpu_temp: None, gpu_mem: None, ..update.clone() };
+        assert_eq!(state.update, settings);
+        assert_eq!(state.update.gpu_temp, None);
+        assert!(state.saved_at > 0);
+        let names = fs::read_dir(file.path().parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["rig1.json"]);
+    }
+
+    #[test]
+    fn replaces_saved_state() {
+        let (_dir, file) = state_file();
+        file.save(&Update::for_temperatures(vec![], vec![], 70, 90, None, None)).unwrap();
+        file.save(&Update::for_temperatures(vec![], vec![], 65, 85, None, Some(80))).unwrap();
+        let update = file.load().unwrap().unwrap().update;
+        assert_eq!((update.target_temp, update.manual_fan_speed), (Some(65), Some(80)));
+    }
+
+    #[test]
+    fn has_nothing_to_restore_without_file() {
+        let (_dir, file) = state_file();
+        assert_eq!(file.load().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_corrupt_file() {
+        let (_dir, file) = state_file();
+        fs::write(file.path(), "{\"saved_at\":17600").unwrap();
+        assert_eq!(file.load().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
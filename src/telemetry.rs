@@ -0,0 +1,419 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A single sample of the telemetry printed by the board while in service mode.
+/// The board prints it as two lines, like
+/// ```text
+/// OCR0B=20 OCR0A(max)=200 fan_pwm=10 auto_mode=1 min_t=65 max_t=66 targ_t=70 pwm_add=-3 cnt=136 osccal=-2
+/// min_mem_t=76 max_mem_t=80 targ_mem_t=90
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Telemetry {
+    /// Value of the OCR0B register, which is the actual PWM duty of the fans.
+    #[cfg_attr(feature = "openapi", schema(example = 20))]
+    pub ocr0b: i32,
+    /// Value of the OCR0A register, the top of the PWM counter (100% duty).
+    #[cfg_attr(feature = "openapi", schema(example = 200))]
+    pub ocr0a_max: i32,
+    /// Fan speed, in percent.
+    #[cfg_attr(feature = "openapi", schema(example = 10))]
+    pub fan_pwm: i32,
+    /// 1 if the board controls the fans automatically, 0 otherwise.
+    #[cfg_attr(feature = "openapi", schema(example = 1))]
+    pub auto_mode: i32,
+    /// The lowest of the reported GPU core temperatures.
+    #[cfg_attr(feature = "openapi", schema(example = 65))]
+    pub min_t: i32,
+    /// The highest of the reported GPU core temperatures.
+    #[cfg_attr(feature = "openapi", schema(example = 66))]
+    pub max_t: i32,
+    /// Target GPU core temperature.
+    #[cfg_attr(feature = "openapi", schema(example = 70))]
+    pub targ_t: i32,
+    /// The last step the automatic mode has applied to the fan speed.
+    #[cfg_attr(feature = "openapi", schema(example = -3))]
+    pub pwm_add: i32,
+    /// Internal counter of the control loop.
+    #[cfg_attr(feature = "openapi", schema(example = 136))]
+    pub cnt: i32,
+    /// Calibration of the internal oscillator of the MCU.
+    #[cfg_attr(feature = "openapi", schema(example = -2))]
+    pub osccal: i32,
+    /// The lowest of the reported GPU VRAM temperatures.
+    #[cfg_attr(feature = "openapi", schema(example = 76))]
+    pub min_mem_t: Option<i32>,
+    /// The highest of the reported GPU VRAM temperatures.
+    #[cfg_attr(feature = "openapi", schema(example = 80))]
+    pub max_mem_t: Option<i32>,
+    /// Target GPU VRAM temperature.
+    #[cfg_attr(feature = "openapi", schema(example = 90))]
+    pub targ_mem_t: Option<i32>,
+    /// When the sample has been received, in seconds since the Unix epoch.
+    pub received_at: u64,
+}
+
+fn key_value_pairs(line: &str) -> impl Iterator<Item = (&str, i32)> {
+    line.split_whitespace().filter_map(|chunk| {
+        let (key, value) = chunk.split_once('=')?;
+        Some((key, value.parse::<i32>().ok()?))
+    })
+}
+
+pub(crate) fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Parses the first line of a telemetry sample (the one starting with `OCR0B=`).
+pub fn parse_main_line(line: &str) -> Option<Telemetry> {
+    if !line.trim_start().starts_with("OCR0B=") {
+        return None;
+    }
+    let mut telemetry = Telemetry {
+        received_at: unix_time_now(),
+        ..Default::default()
+    };
+    for (key, value) in key_value_pairs(line) {
+        match key {
+            "OCR0B" => telemetry.ocr0b = value,
+            "OCR0A(max)" => telemetry.ocr0a_max = value,
+            "fan_pwm" => telemetry.fan_pwm = value,
+            "auto_mode" => telemetry.auto_mode = value,
+            "min_t" => telemetry.min_t = value,
+            "max_t" => telemetry.max_t = value,
+            "targ_t" => telemetry.targ_t = value,
+            "pwm_add" => telemetry.pwm_add = value,
+            "cnt" => telemetry.cnt = value,
+            "osccal" => telemetry.osccal = value,
+            _ => log::debug!("Unknown telemetry field {:?}", key),
+        }
+    }
+    Some(telemetry)
+}
+
+/// Parses the second line of a telemetry sample (the one starting with `min_mem_t=`)
+/// and fills the VRAM-related fields of the given sample.
+pub fn parse_mem_line(line: &str, telemetry: &mut Telemetry) -> bool {
+    if !line.trim_start().starts_with("min_mem_t=") {
+        return false;
+    }
+    for (key, value) in key_value_pairs(line) {
+        match key {
+            "min_mem_t" => telemetry.min_mem_t = Some(value),
+            "max_mem_t" => telemetry.max_mem_t = Some(value),
+            "targ_mem_t" => telemetry.targ_mem_t = Some(value),
+            _ => log::debug!("Unknown telemetry field {:?}", key),
+        }
+    }
+    true
+}
+
+/// Assembles telemetry samples from the lines printed by the device.
+#[derive(Default)]
+pub struct TelemetryParser {
+    pending: Option<Telemetry>,
+}
+
+impl TelemetryParser {
+    /// Consumes a complete line of the device's output.
+    /// Returns a sample once it's complete.
+    pub fn feed_line(&mut self, line: &str) -> Option<Telemetry> {
+        if let Some(telemetry) = parse_main_line(line) {
+            // Normally the VRAM line follows immediately. If it didn't,
+            // the previous sample is as complete as it can get.
+            return self.pending.replace(telemetry);
+        }
+        if let Some(mut telemetry) = self.pending.take() {
+            if parse_mem_line(line, &mut telemetry) {
+                return Some(telemetry);
+            }
+            self.pending = Some(telemetry);
+        }
+        None
+    }
+}
+
+/// Beginnings of the lines the board prints on its own while in service mode: the two lines
+/// of a sample, followed by the `F1=.. F12=` and `M1=.. M12=` lines, which are not parsed.
+const TELEMETRY_LINE_PREFIXES: &[&[u8]] = &[b"OCR0B=", b"min_mem_t=", b"F1=", b"M1="];
+
+/// A piece of the device's output, sorted out by [`OutputRouter`].
+#[derive(Debug, PartialEq)]
+pub enum RoutedOutput {
+    /// A complete line of telemetry, including the line end.
+    Telemetry(Vec<u8>),
+    /// Anything else, which is a reply to a command if there is one being executed.
+    Other(Vec<u8>),
+}
+
+#[derive(Default, PartialEq)]
+enum LineKind {
+    #[default]
+    Undecided,
+    Telemetry,
+    Other,
+}
+
+/// Tells telemetry lines from everything else in the device's output.
+/// The bytes of other lines are passed on as soon as it's clear they are not telemetry,
+/// so replies without a line end (such as JSON objects) are not held back.
+/// An empty line right after telemetry is held back until the next line tells
+/// whether it separates the samples or starts a reply.
+#[derive(Default)]
+pub struct OutputRouter {
+    line: Vec<u8>,
+    kind: LineKind,
+    after_telemetry: bool,
+    held_blank_line: Vec<u8>,
+}
+
+impl OutputRouter {
+    pub fn feed_byte(&mut self, byte: u8) -> Option<RoutedOutput> {
+        if self.kind == LineKind::Other {
+            if byte == b'\n' {
+                self.finish_line();
+            }
+            return Some(RoutedOutput::Other(vec![byte]));
+        }
+        self.line.push(byte);
+        if self.kind == LineKind::Telemetry {
+            if byte != b'\n' {
+                return None;
+            }
+            let line = std::mem::take(&mut self.line);
+            self.finish_line();
+            self.after_telemetry = true;
+            return Some(RoutedOutput::Telemetry(line));
+        }
+        let is_blank = self.line.iter().all(u8::is_ascii_whitespace);
+        if byte == b'\n' {
+            // The board may separate telemetry samples with an empty line
+            let is_held = is_blank && self.after_telemetry;
+            let line = std::mem::take(&mut self.line);
+            self.finish_line();
+            if is_held {
+                self.held_blank_line = line;
+                return None;
+            }
+            return Some(RoutedOutput::Other(self.with_held_blank_line(line)));
+        }
+        if is_blank {
+            return None;
+        }
+        if TELEMETRY_LINE_PREFIXES
+            .iter()
+            .any(|prefix| self.line.starts_with(prefix))
+        {
+            self.kind = LineKind::Telemetry;
+            let held = std::mem::take(&mut self.held_blank_line);
+            return (!held.is_empty()).then_some(RoutedOutput::Telemetry(held));
+        }
+        if TELEMETRY_LINE_PREFIXES
+            .iter()
+            .any(|prefix| prefix.starts_with(&self.line))
+        {
+            return None;
+        }
+        self.kind = LineKind::Other;
+        let line = std::mem::take(&mut self.line);
+        Some(RoutedOutput::Other(self.with_held_blank_line(line)))
+    }
+
+    /// Puts the empty line held back in front of the line, which is not telemetry.
+    fn with_held_blank_line(&mut self, line: Vec<u8>) -> Vec<u8> {
+        let mut held = std::mem::take(&mut self.held_blank_line);
+        held.extend(line);
+        held
+    }
+
+    fn finish_line(&mut self) {
+        self.line.clear();
+        self.kind = LineKind::Undecided;
+        self.after_telemetry = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN_LINE: &str = concat!(
+        "OCR0B=20 OCR0A(max)=200 fan_pwm=10 auto_mode=1 min_t=65 max_t=66 targ_t=70 ",
+        "pwm_add=-3 cnt=136 osccal=-2"
+    );
+    const MEM_LINE: &str = "min_mem_t=76 max_mem_t=80 targ_mem_t=90";
+
+    fn readme_sample() -> Telemetry {
+        Telemetry {
+            ocr0b: 20,
+            ocr0a_max: 200,
+            fan_pwm: 10,
+            auto_mode: 1,
+            min_t: 65,
+            max_t: 66,
+            targ_t: 70,
+            pwm_add: -3,
+            cnt: 136,
+            osccal: -2,
+            min_mem_t: Some(76),
+            max_mem_t: Some(80),
+            targ_mem_t: Some(90),
+            received_at: 0,
+        }
+    }
+
+    #[test]
+    fn parses_main_line() {
+        let telemetry = parse_main_line(MAIN_LINE).unwrap();
+        assert!(telemetry.received_at > 0);
+        assert_eq!(
+            telemetry,
+            Telemetry {
+                min_mem_t: None,
+                max_mem_t: None,
+                targ_mem_t: None,
+                received_at: telemetry.received_at,
+                ..readme_sample()
+            }
+        );
+        assert_eq!(parse_main_line(MEM_LINE), None);
+        assert_eq!(parse_main_line("settings accepted"), None);
+    }
+
+    #[test]
+    fn parses_mem_line() {
+        let mut telemetry = Telemetry::default();
+        assert!(parse_mem_line(MEM_LINE, &mut telemetry));
+        assert_eq!(
+            (telemetry.min_mem_t, telemetry.max_mem_t, telemetry.targ_mem_t),
+            (Some(76), Some(80), Some(90))
+        );
+        assert!(!parse_mem_line(MAIN_LINE, &mut telemetry));
+    }
+
+    #[test]
+    fn skips_unknown_and_garbled_fields() {
+        let telemetry =
+            parse_main_line("OCR0B=20 fan_pwm=1O new_field=5 max_t= cnt=7 =3 junk").unwrap();
+        assert_eq!(
+            telemetry,
+            Telemetry {
+                ocr0b: 20,
+                cnt: 7,
+                received_at: telemetry.received_at,
+                ..Default::default()
+            }
+        );
+        let mut telemetry = Telemetry::default();
+        assert!(parse_mem_line("min_mem_t=x max_mem_t=80 fan=1", &mut telemetry));
+        assert_eq!((telemetry.min_mem_t, telemetry.max_mem_t), (None, Some(80)));
+    }
+
+    #[test]
+    fn assembles_samples() {
+        let mut parser = TelemetryParser::default();
+        assert_eq!(parser.feed_line(MAIN_LINE), None);
+        let telemetry = parser.feed_line(MEM_LINE).unwrap();
+        assert_eq!(
+            telemetry,
+            Telemetry {
+                received_at: telemetry.received_at,
+                ..readme_sample()
+            }
+        );
+        // Without the VRAM line, the sample is complete once the next one starts
+        assert_eq!(parser.feed_line(MAIN_LINE), None);
+        assert_eq!(parser.feed_line("settings accepted"), None);
+        let telemetry = parser.feed_line(MAIN_LINE).unwrap();
+        assert_eq!((telemetry.cnt, telemetry.min_mem_t), (136, None));
+        // A stray VRAM line without a sample is ignored
+        let mut parser = TelemetryParser::default();
+        assert_eq!(parser.feed_line(MEM_LINE), None);
+    }
+
+    /// Feeds the output to the router, returning the pieces it has passed on,
+    /// with the telemetry marked by a "T:" prefix.
+    fn route(router: &mut OutputRouter, output: &str) -> Vec<String> {
+        output
+            .bytes()
+            .filter_map(|byte| router.feed_byte(byte))
+            .map(|routed| match routed {
+                RoutedOutput::Telemetry(line) => format!("T:{}", String::from_utf8_lossy(&line)),
+                RoutedOutput::Other(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn holds_back_possible_telemetry_prefixes() {
+        let mut router = OutputRouter::default();
+        assert_eq!(route(&mut router, "O"), Vec::<String>::new());
+        assert_eq!(route(&mut router, "CR0"), Vec::<String>::new());
+        assert_eq!(route(&mut router, "A"), vec!["OCR0A"]);
+        assert_eq!(route(&mut router, "=1\n"), vec!["=", "1", "\n"]);
+        assert_eq!(route(&mut router, "m"), Vec::<String>::new());
+        assert_eq!(route(&mut router, "a"), vec!["ma"]);
+        assert_eq!(route(&mut router, "x\n"), vec!["x", "\n"]);
+        // A JSON reply isn't held back until a line end that never comes
+        assert_eq!(route(&mut router, "{\"a\":1}").concat(), "{\"a\":1}");
+    }
+
+    #[test]
+    fn routes_blank_line_between_samples() {
+        let mut router = OutputRouter::default();
+        let sample = format!("{}\n{}\n\n", MAIN_LINE, MEM_LINE);
+        assert_eq!(
+            route(&mut router, &sample),
+            vec![format!("T:{}\n", MAIN_LINE), format!("T:{}\n", MEM_LINE)]
+        );
+        assert_eq!(
+            route(&mut router, &format!("{}\n", MAIN_LINE)),
+            vec!["T:\n".to_string(), format!("T:{}\n", MAIN_LINE)]
+        );
+        // The empty line ending a reply stays with the reply
+        route(&mut router, &format!("{}\n", MEM_LINE));
+        assert_eq!(route(&mut router, "service mode ON \n\n").concat(), "service mode ON \n\n");
+    }
+
+    #[test]
+    fn keeps_reply_starting_with_blank_line_after_telemetry() {
+        let mut router = OutputRouter::default();
+        let output = format!("{}\n{}\n\nservice mode OFF  \n\n", MAIN_LINE, MEM_LINE);
+        let routed = route(&mut router, &output);
+        assert_eq!(routed.iter().filter(|piece| piece.starts_with("T:")).count(), 2);
+        let other = routed.iter().filter(|piece| !piece.starts_with("T:"));
+        assert_eq!(other.cloned().collect::<String>(), "\nservice mode OFF  \n\n");
+        let mut router = OutputRouter::default();
+        let output = format!("{}\n\n{{\"fw_version\":1271}}", MEM_LINE);
+        assert_eq!(route(&mut router, &output)[1..].concat(), "\n{\"fw_version\":1271}");
+    }
+
+    #[test]
+    fn routes_fan_lines() {
+        let mut router = OutputRouter::default();
+        let fans = "F1=1200 F2=1180 F3=0 F4=0 F5=0 F6=0 F7=0 F8=0 F9=0 F10=0 F11=0 F12=0 \n";
+        let marks = "M1=1 M2=1 M3=0 M4=0 M5=0 M6=0 M7=0 M8=0 M9=0 M10=0 M11=0 M12=0 \n";
+        let output =
+            format!("{}\n{}\n{}{}service mode OFF  \n\n", MAIN_LINE, MEM_LINE, fans, marks);
+        let routed = route(&mut router, &output);
+        assert_eq!(&routed[2..4], [format!("T:{}", fans), format!("T:{}", marks)]);
+        assert_eq!(routed[4..].concat(), "service mode OFF  \n\n");
+    }
+
+    #[test]
+    fn separates_reply_from_telemetry() {
+        let mut router = OutputRouter::default();
+        let output = format!(
+            "{}\nsettings accepted\n{}\n\n{}\n{}\n\n",
+            MAIN_LINE, MEM_LINE, MAIN_LINE, MEM_LINE
+        );
+        let routed = route(&mut router, &output);
+        let (telemetry, other): (Vec<_>, Vec<_>) =
+            routed.iter().partition(|piece| piece.starts_with("T:"));
+        assert_eq!(other.into_iter().cloned().collect::<String>(), "settings accepted\n");
+        // The last empty line is held back until the next line
+        assert_eq!(telemetry.len(), 5);
+    }
+}
@@ -0,0 +1,98 @@
+//! Collecting GPU temperatures on the rig itself, instead of waiting for them at `/api/update`.
+//!
+//! A [`TemperatureSource`] reads the temperatures of all GPUs, and a [`TemperatureCollector`]
+//! sends them to the boards on an interval, together with the settings last given to the boards.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::registry::Board;
+
+/// How often the collector checks whether it's been asked to stop.
+const COLLECTOR_POLL_INTERVAL_MS: u64 = 100;
+
+/// Temperatures of the GPUs of the rig, in °C, one per GPU.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct GpuTemperatures {
+    /// GPU core temperatures.
+    pub core: Vec<i32>,
+    /// GPU VRAM temperatures. Empty, if not every GPU reports them.
+    pub mem: Vec<i32>,
+}
+
+/// Something that knows the temperatures of the GPUs.
+pub trait TemperatureSource: Send {
+    fn read(&mut self) -> io::Result<GpuTemperatures>;
+}
+
+/// Reads the temperatures in the background and sends them to the boards.
+pub struct TemperatureCollector {
+    exit_flag: Arc<AtomicBool>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl TemperatureCollector {
+    /// Starts reading the source every `interval`, sending the temperatures to all the boards.
+    pub fn spawn(
+        mut source: Box<dyn TemperatureSource>,
+        boards: Vec<Arc<Board>>,
+        interval: Duration,
+    ) -> Self {
+        let exit_flag = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let exit_flag = Arc::clone(&exit_flag);
+            std::thread::spawn(move || {
+                while !exit_flag.load(Ordering::Relaxed) {
+                    let deadline = Instant::now() + interval;
+                    match source.read() {
+                        Ok(temperatures) if temperatures.core.is_empty() => {
+                            log::warn!("No GPU temperatures have been found");
+                        }
+                        Ok(temperatures) => {
+                            log::debug!("Collected GPU temperatures {:?}", temperatures);
+                            send_temperatures(&boards, &temperatures);
+                        }
+                        Err(e) => log::error!("Unable to read GPU temperatures: {}", e),
+                    }
+                    while Instant::now() < deadline && !exit_flag.load(Ordering::Relaxed) {
+                        std::thread::sleep(Duration::from_millis(COLLECTOR_POLL_INTERVAL_MS));
+                    }
+                }
+            })
+        };
+        Self {
+            exit_flag,
+            handle: Some(handle),
+        }
+    }
+
+    /// Stops collecting and waits for the last round to finish.
+    pub fn stop(&mut self) {
+        self.exit_flag.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+impl Drop for TemperatureCollector {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn send_temperatures(boards: &[Arc<Board>], temperatures: &GpuTemperatures) {
+    for board in boards {
+        let result = futures::executor::block_on(
+            board
+                .autofan
+                .send_temperatures(temperatures.core.clone(), temperatures.mem.clone()),
+        );
+        if let Err(e) = result {
+            log::error!("Unable to send GPU temperatures to board {:?}: {}", board.id, e);
+        }
+    }
+}